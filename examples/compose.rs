@@ -1,5 +1,6 @@
-use nuclear::functional::{handler, middleware};
-use nuclear::prelude::{Handler, Request, Response, Result};
+use nuclear::functional::{before, map_response, middleware, request_handler};
+use nuclear::http::HeaderValue;
+use nuclear::prelude::{Handler, MiddlewareStack, Request, Response, Result};
 
 async fn outer(req: Request, next: &dyn Handler) -> Result<Response> {
     println!("outer: before next");
@@ -15,14 +16,29 @@ async fn inner(req: Request, next: &dyn Handler) -> Result<Response> {
     res
 }
 
-fn compose(h: impl Handler) -> impl Handler {
-    let inner = middleware(inner);
-    let outer = middleware(outer);
-    h.wrap(inner).wrap(outer)
+async fn log(req: Request) -> Result<Request> {
+    println!("log: {} {}", req.method(), req.uri());
+    Ok(req)
+}
+
+async fn powered_by(mut res: Response) -> Response {
+    let value = HeaderValue::from_static("nuclear");
+    res.headers_mut().insert("x-powered-by", value);
+    res
+}
+
+fn compose(h: impl Handler + 'static) -> Box<dyn Handler> {
+    let mut stack = MiddlewareStack::new();
+    stack
+        .push(middleware(outer))
+        .push(middleware(inner))
+        .push(before(log))
+        .push(map_response(powered_by));
+    stack.apply(h)
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let h = compose(handler(|_| async { println!("hello") }));
+    let h = compose(request_handler(|_| async { println!("hello") }));
     h.into_server().run("127.0.0.1:8080").await
 }
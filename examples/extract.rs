@@ -0,0 +1,61 @@
+use nuclear::extract::{Cookies, Path, Query, State};
+use nuclear::functional::{handler, ref_handler};
+use nuclear::prelude::{Handler, Result};
+use nuclear::response::Json;
+use nuclear::router::SimpleRouter;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+struct App {
+    count: AtomicUsize,
+}
+
+#[derive(Deserialize)]
+struct Pagination {
+    page: usize,
+    per_page: usize,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Post {
+    title: String,
+}
+
+async fn get_posts(Query(p): Query<Pagination>, cookies: Cookies) -> String {
+    let user = cookies.get("user").unwrap_or("anonymous");
+    format!("page {} ({} per page) for {}", p.page, p.per_page, user)
+}
+
+async fn get_post(Path(pid): Path<PostId>, State(app): State<App>) -> String {
+    let count = app.count.fetch_add(1, Ordering::Relaxed) + 1;
+    format!("post {} => {}", pid.pid, count)
+}
+
+async fn create_post(app: &App, Json(post): Json<Post>) -> Json<Post> {
+    app.count.fetch_add(1, Ordering::Relaxed);
+    Json(post)
+}
+
+#[derive(Deserialize)]
+struct PostId {
+    pid: u64,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let mut router = SimpleRouter::new();
+    router
+        .at("/posts")
+        .get(handler(get_posts).boxed())
+        .post(ref_handler(create_post).boxed());
+    router.at("/posts/:pid").get(handler(get_post).boxed());
+
+    let app = App {
+        count: AtomicUsize::new(0),
+    };
+    let h = router.with_state(Arc::new(app));
+    h.into_server().run("127.0.0.1:8080").await
+}
@@ -27,6 +27,6 @@ async fn recover(req: Request, next: &dyn Handler) -> Result<Response> {
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let h: _ = handler(json_echo).wrap(middleware(recover));
+    let h = handler(json_echo).wrap(middleware(recover));
     h.into_server().run("127.0.0.1:8080").await
 }
@@ -0,0 +1,45 @@
+use nuclear::extract::State;
+use nuclear::functional::handler;
+use nuclear::prelude::{Handler, Result};
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+struct Db {
+    queries: AtomicUsize,
+}
+
+impl Db {
+    async fn connect() -> Result<Self> {
+        println!("db: connected");
+        Ok(Self {
+            queries: AtomicUsize::new(0),
+        })
+    }
+}
+
+async fn query(State(db): State<Db>) -> String {
+    let n = db.queries.fetch_add(1, Ordering::Relaxed) + 1;
+    format!("query {}", n)
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let mut server = handler(query).into_server();
+    server
+        .startup_state(|| async { Ok(Arc::new(Db::connect().await?)) })
+        .on_shutdown(|| async {
+            println!("db: closed");
+            Ok(())
+        });
+    server.shutdown_timeout(Duration::from_secs(5));
+    server.verify_state()?;
+
+    let signal = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+    let aborted = server.run_with_shutdown("127.0.0.1:8080", signal).await?;
+    println!("aborted connections: {}", aborted);
+    Ok(())
+}
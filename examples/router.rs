@@ -2,6 +2,7 @@ use nuclear::error::StatusError;
 use nuclear::functional::{ref_handler, ref_middleware};
 use nuclear::prelude::{Handler, Request, Response, Result};
 use nuclear::router::{SimpleRouter, SimpleRouterExt};
+use nuclear::state;
 
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
@@ -18,10 +19,10 @@ impl App {
     }
 
     fn into_handler(self) -> impl Handler {
-        let get_hello: _ = ref_handler(Self::get_hello);
-        let get_world: _ = ref_handler(Self::get_world);
-        let not_found: _ = ref_handler(Self::not_found);
-        let recover: _ = ref_middleware(Self::recover);
+        let get_hello = ref_handler(Self::get_hello);
+        let get_world = ref_handler(Self::get_world);
+        let not_found = ref_handler(Self::not_found);
+        let recover = ref_middleware(Self::recover);
 
         let mut router: SimpleRouter = SimpleRouter::new();
 
@@ -60,5 +61,6 @@ impl App {
 #[tokio::main]
 async fn main() -> Result<()> {
     let h = App::new().into_handler();
+    state::verify(&h)?;
     h.into_server().run("127.0.0.1:8080").await
 }
@@ -1,9 +1,24 @@
-use crate::http::{self, Mime};
+use crate::error::StatusError;
+use crate::http::{self, HeaderMap, Mime, StatusCode};
 use crate::internal_prelude::*;
+use crate::validate::{validate, Validate};
+
+use std::io::Read;
+use std::marker::PhantomData;
+use std::mem;
+use std::pin::Pin;
+use std::task::{Context, Poll};
 
 use async_trait::async_trait;
-use bytes::Bytes;
+use bytes::{Buf, Bytes, BytesMut};
+use futures::stream::Stream;
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
+use smallvec::SmallVec;
+
+mod stream;
+
+pub use self::stream::BodyStream;
 
 #[derive(Debug, thiserror::Error)]
 pub enum BodyError {
@@ -13,6 +28,138 @@ pub enum BodyError {
     InvalidFormat { source: Error },
     #[error("ContentTypeMismatch")]
     ContentTypeMismatch,
+    #[error("UnsupportedEncoding")]
+    UnsupportedEncoding,
+    #[error("InvalidEncoding: {}", .source)]
+    InvalidEncoding { source: Error },
+    #[error("Timeout")]
+    Timeout,
+}
+
+impl BodyError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            BodyError::LengthLimitExceeded => StatusCode::PAYLOAD_TOO_LARGE,
+            BodyError::InvalidFormat { .. } => StatusCode::BAD_REQUEST,
+            BodyError::ContentTypeMismatch => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            BodyError::UnsupportedEncoding => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            BodyError::InvalidEncoding { .. } => StatusCode::BAD_REQUEST,
+            BodyError::Timeout => StatusCode::REQUEST_TIMEOUT,
+        }
+    }
+}
+
+impl From<BodyError> for Response {
+    fn from(e: BodyError) -> Self {
+        StatusError::new(e.status()).into()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ContentCoding {
+    Identity,
+    #[cfg(feature = "gzip")]
+    Gzip,
+    #[cfg(feature = "deflate")]
+    Deflate,
+    #[cfg(feature = "br")]
+    Br,
+}
+
+/// Content codings enabled by cargo features
+const CONTENT_CODINGS: &[(&str, ContentCoding)] = &[
+    ("identity", ContentCoding::Identity),
+    #[cfg(feature = "gzip")]
+    ("gzip", ContentCoding::Gzip),
+    #[cfg(feature = "gzip")]
+    ("x-gzip", ContentCoding::Gzip),
+    #[cfg(feature = "deflate")]
+    ("deflate", ContentCoding::Deflate),
+    #[cfg(feature = "br")]
+    ("br", ContentCoding::Br),
+];
+
+impl ContentCoding {
+    fn from_token(token: &str) -> Option<Self> {
+        CONTENT_CODINGS
+            .iter()
+            .find(|&&(name, _)| token.eq_ignore_ascii_case(name))
+            .map(|&(_, coding)| coding)
+    }
+
+    fn decoder<'a>(self, bytes: &'a [u8]) -> Box<dyn Read + 'a> {
+        match self {
+            ContentCoding::Identity => Box::new(bytes),
+            #[cfg(feature = "gzip")]
+            ContentCoding::Gzip => Box::new(flate2::read::GzDecoder::new(bytes)),
+            #[cfg(feature = "deflate")]
+            ContentCoding::Deflate => Box::new(flate2::read::ZlibDecoder::new(bytes)),
+            #[cfg(feature = "br")]
+            ContentCoding::Br => Box::new(brotli_decompressor::Decompressor::new(bytes, 4096)),
+        }
+    }
+}
+
+/// Content codings in the order they were applied
+pub(crate) type ContentCodings = SmallVec<[ContentCoding; 2]>;
+
+pub(crate) fn parse_content_encoding(headers: &HeaderMap) -> Result<ContentCodings, BodyError> {
+    let mut codings = ContentCodings::new();
+    for value in headers.get_all(http::header::CONTENT_ENCODING) {
+        let value = value.to_str().map_err(|_| BodyError::UnsupportedEncoding)?;
+        for token in value.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+            match ContentCoding::from_token(token) {
+                Some(coding) => codings.push(coding),
+                None => return Err(BodyError::UnsupportedEncoding),
+            }
+        }
+    }
+    Ok(codings)
+}
+
+/// Decodes `bytes` and applies `length_limit` to the decoded size.
+pub(crate) fn decode_content(
+    mut bytes: Bytes,
+    codings: &[ContentCoding],
+    length_limit: usize,
+) -> Result<Bytes, BodyError> {
+    if bytes.is_empty() {
+        return Ok(bytes);
+    }
+    for coding in codings.iter().rev() {
+        let reader = coding.decoder(&bytes);
+        let mut buf: Vec<u8> = Vec::new();
+        let limit = (length_limit as u64).saturating_add(1);
+        if let Err(e) = reader.take(limit).read_to_end(&mut buf) {
+            return Err(BodyError::InvalidEncoding { source: e.into() });
+        }
+        if buf.len() > length_limit {
+            return Err(BodyError::LengthLimitExceeded);
+        }
+        bytes = Bytes::from(buf);
+    }
+    Ok(bytes)
+}
+
+/// Decoding output up to this size is cheap enough to run on the executor.
+const INLINE_DECODE_LIMIT: usize = 64 * 1024;
+
+/// Like [`decode_content`], but runs on the blocking pool
+/// when the decoded size may exceed [`INLINE_DECODE_LIMIT`].
+pub(crate) async fn decode_content_async(
+    bytes: Bytes,
+    codings: ContentCodings,
+    length_limit: usize,
+) -> Result<Bytes, BodyError> {
+    let identity = codings.iter().all(|&c| c == ContentCoding::Identity);
+    if identity || length_limit <= INLINE_DECODE_LIMIT {
+        return decode_content(bytes, &codings, length_limit);
+    }
+    let task = tokio::task::spawn_blocking(move || decode_content(bytes, &codings, length_limit));
+    match task.await {
+        Ok(ret) => ret,
+        Err(e) => Err(BodyError::InvalidEncoding { source: e.into() }),
+    }
 }
 
 fn parse_mime(req: &Request) -> Option<Mime> {
@@ -24,8 +171,6 @@ fn parse_mime(req: &Request) -> Option<Mime> {
         .ok()
 }
 
-struct FullBody(Bytes);
-
 #[derive(Debug, Clone)]
 pub struct JsonParser {
     length_limit: usize,
@@ -50,7 +195,7 @@ impl JsonParser {
     where
         T: Deserialize<'r>,
     {
-        let ct_check = parse_mime(&req)
+        let ct_check = parse_mime(req)
             .map(|mime| mime.type_() == mime::APPLICATION && mime.subtype() == mime::JSON)
             .unwrap_or(false);
 
@@ -58,16 +203,11 @@ impl JsonParser {
             return Err(BodyError::ContentTypeMismatch.into());
         }
 
-        {
-            if req.extensions().get::<FullBody>().is_none() {
-                let full_body = FullBody(req.body_bytes(self.length_limit).await?);
-                req.extensions_mut().insert(full_body);
-            }
-        }
+        req.buffer_body(self.length_limit).await?;
 
-        let full_body = req.extensions().get::<FullBody>().unwrap();
+        let full_body = req.buffered_body().unwrap();
 
-        match serde_json::from_slice(&*full_body.0) {
+        match serde_json::from_slice(full_body) {
             Ok(value) => Ok(value),
             Err(e) => Err(BodyError::InvalidFormat { source: e.into() }.into()),
         }
@@ -78,6 +218,7 @@ impl JsonParser {
 pub trait JsonExt {
     async fn parse_json<'r, T: Deserialize<'r>>(&'r mut self, parser: &JsonParser) -> Result<T>;
     async fn json<'r, T: Deserialize<'r>>(&'r mut self) -> Result<T>;
+    async fn valid_json<'r, T: Deserialize<'r> + Validate>(&'r mut self) -> Result<T>;
 }
 
 #[async_trait]
@@ -93,4 +234,333 @@ impl JsonExt for Request {
         };
         self.parse_json(&parser).await
     }
+
+    async fn valid_json<'r, T: Deserialize<'r> + Validate>(&'r mut self) -> Result<T> {
+        let value: T = self.json().await?;
+        validate(&value)?;
+        Ok(value)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FormParser {
+    length_limit: usize,
+}
+
+impl Default for FormParser {
+    fn default() -> Self {
+        Self {
+            length_limit: Self::DEFAULT_LENGTH_LIMIT,
+        }
+    }
+}
+
+impl FormParser {
+    const DEFAULT_LENGTH_LIMIT: usize = 32 * 1024;
+
+    pub fn length_limit(&mut self, limit: usize) {
+        self.length_limit = limit;
+    }
+
+    pub async fn parse<T>(&self, req: &mut Request) -> Result<T>
+    where
+        T: DeserializeOwned,
+    {
+        let ct_check = parse_mime(req)
+            .map(|mime| mime.essence_str() == mime::APPLICATION_WWW_FORM_URLENCODED.as_ref())
+            .unwrap_or(false);
+
+        if !ct_check {
+            return Err(BodyError::ContentTypeMismatch.into());
+        }
+
+        let full_body = req.buffer_body(self.length_limit).await?;
+
+        match serde_urlencoded::from_bytes(&full_body) {
+            Ok(value) => Ok(value),
+            Err(e) => Err(BodyError::InvalidFormat { source: e.into() }.into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NdjsonParser {
+    line_limit: usize,
+}
+
+impl Default for NdjsonParser {
+    fn default() -> Self {
+        Self {
+            line_limit: Self::DEFAULT_LINE_LIMIT,
+        }
+    }
+}
+
+impl NdjsonParser {
+    const DEFAULT_LINE_LIMIT: usize = 32 * 1024;
+
+    pub fn line_limit(&mut self, limit: usize) {
+        self.line_limit = limit;
+    }
+
+    /// Takes the body out of `req` and parses it line by line.
+    ///
+    /// Compressed bodies are rejected because decoding requires buffering.
+    pub fn parse<T>(&self, req: &mut Request) -> Result<NdjsonStream<T>>
+    where
+        T: DeserializeOwned,
+    {
+        let ct_check = parse_mime(req)
+            .map(|mime| {
+                mime.type_() == mime::APPLICATION
+                    && matches!(
+                        mime.subtype().as_str(),
+                        "x-ndjson" | "jsonl" | "x-jsonlines"
+                    )
+            })
+            .unwrap_or(false);
+
+        if !ct_check {
+            return Err(BodyError::ContentTypeMismatch.into());
+        }
+
+        let codings = parse_content_encoding(req.headers())?;
+        if codings.iter().any(|&c| c != ContentCoding::Identity) {
+            return Err(BodyError::UnsupportedEncoding.into());
+        }
+
+        Ok(NdjsonStream {
+            body: mem::take(req.body_mut()),
+            buf: BytesMut::new(),
+            line_limit: self.line_limit,
+            eof: false,
+            skipping: false,
+            _marker: PhantomData,
+        })
+    }
+}
+
+/// A stream of values parsed from a NDJSON body
+pub struct NdjsonStream<T> {
+    body: Body,
+    buf: BytesMut,
+    line_limit: usize,
+    eof: bool,
+    /// Set while discarding the rest of an over-limit line
+    skipping: bool,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> NdjsonStream<T> {
+    fn next_line(&mut self) -> Option<Result<Bytes, BodyError>> {
+        loop {
+            let newline = self.buf.iter().position(|&b| b == b'\n');
+            if self.skipping {
+                match newline {
+                    Some(pos) => {
+                        self.buf.advance(pos + 1);
+                        self.skipping = false;
+                        continue;
+                    }
+                    None => {
+                        self.buf.clear();
+                        return None;
+                    }
+                }
+            }
+
+            let line = match newline {
+                Some(pos) => self.buf.split_to(pos + 1),
+                None if self.eof && !self.buf.is_empty() => self.buf.split(),
+                None if self.buf.len() > self.line_limit => {
+                    self.buf.clear();
+                    self.skipping = true;
+                    return Some(Err(BodyError::LengthLimitExceeded));
+                }
+                None => return None,
+            };
+
+            let line = line.freeze();
+            let len = trim_line(&line).len();
+            if len > self.line_limit {
+                return Some(Err(BodyError::LengthLimitExceeded));
+            }
+            if len > 0 {
+                return Some(Ok(line.slice(..len)));
+            }
+        }
+    }
+}
+
+fn trim_line(mut line: &[u8]) -> &[u8] {
+    while let [rest @ .., b'\n' | b'\r' | b' ' | b'\t'] = line {
+        line = rest;
+    }
+    line
+}
+
+impl<T> Stream for NdjsonStream<T>
+where
+    T: DeserializeOwned,
+{
+    type Item = Result<T>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(line) = this.next_line() {
+                let ret = line.and_then(|line| {
+                    serde_json::from_slice(&line)
+                        .map_err(|e| BodyError::InvalidFormat { source: e.into() })
+                });
+                return Poll::Ready(Some(ret.map_err(Into::into)));
+            }
+
+            if this.eof {
+                return Poll::Ready(None);
+            }
+
+            match futures::ready!(Pin::new(&mut this.body).poll_next(cx)) {
+                Some(Ok(bytes)) => this.buf.extend_from_slice(&bytes),
+                Some(Err(e)) => return Poll::Ready(Some(Err(e.into()))),
+                None => this.eof = true,
+            }
+        }
+    }
+}
+
+pub trait NdjsonExt {
+    fn parse_ndjson<T: DeserializeOwned>(
+        &mut self,
+        parser: &NdjsonParser,
+    ) -> Result<NdjsonStream<T>>;
+    fn ndjson<T: DeserializeOwned>(&mut self) -> Result<NdjsonStream<T>>;
+}
+
+impl NdjsonExt for Request {
+    fn parse_ndjson<T: DeserializeOwned>(
+        &mut self,
+        parser: &NdjsonParser,
+    ) -> Result<NdjsonStream<T>> {
+        parser.parse(self)
+    }
+
+    fn ndjson<T: DeserializeOwned>(&mut self) -> Result<NdjsonStream<T>> {
+        let parser = match self.extensions().get::<NdjsonParser>() {
+            Some(p) => p.clone(),
+            None => NdjsonParser::default(),
+        };
+        self.parse_ndjson(&parser)
+    }
+}
+
+#[test]
+fn content_encoding() {
+    let mut headers = HeaderMap::new();
+    assert!(parse_content_encoding(&headers).unwrap().is_empty());
+
+    headers.insert(http::header::CONTENT_ENCODING, "identity".parse().unwrap());
+    let codings = parse_content_encoding(&headers).unwrap();
+    assert_eq!(codings.as_slice(), &[ContentCoding::Identity]);
+
+    let bytes = decode_content(Bytes::from_static(b"hello"), &codings, 5).unwrap();
+    assert_eq!(bytes, "hello");
+    let ret = decode_content(Bytes::from_static(b"hello"), &codings, 4);
+    assert!(matches!(ret, Err(BodyError::LengthLimitExceeded)));
+
+    headers.insert(http::header::CONTENT_ENCODING, "compress".parse().unwrap());
+    let ret = parse_content_encoding(&headers);
+    assert!(matches!(ret, Err(BodyError::UnsupportedEncoding)));
+}
+
+#[cfg(feature = "gzip")]
+#[test]
+fn gzip_bomb() {
+    use std::io::Write;
+
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+    encoder.write_all(&[0; 1024 * 1024]).unwrap();
+    let compressed = Bytes::from(encoder.finish().unwrap());
+    assert!(compressed.len() < 32 * 1024);
+
+    let codings = [ContentCoding::Gzip];
+    let ret = decode_content(compressed.clone(), &codings, 32 * 1024);
+    assert!(matches!(ret, Err(BodyError::LengthLimitExceeded)));
+
+    let bytes = decode_content(compressed, &codings, 1024 * 1024).unwrap();
+    assert_eq!(bytes.len(), 1024 * 1024);
+}
+
+#[cfg(feature = "gzip")]
+#[cfg(test)]
+#[tokio::test]
+async fn gzip_blocking_pool() {
+    use std::io::Write;
+
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+    encoder.write_all(&[7; 256 * 1024]).unwrap();
+    let compressed = Bytes::from(encoder.finish().unwrap());
+
+    let codings: ContentCodings = [ContentCoding::Gzip].iter().copied().collect();
+    let bytes = decode_content_async(compressed.clone(), codings.clone(), 1024 * 1024)
+        .await
+        .unwrap();
+    assert_eq!(bytes.len(), 256 * 1024);
+
+    let ret = decode_content_async(compressed, codings, 128 * 1024).await;
+    assert!(matches!(ret, Err(BodyError::LengthLimitExceeded)));
+}
+
+#[test]
+fn ndjson_stream() {
+    use futures::executor::block_on;
+    use futures::stream::TryStreamExt;
+
+    let body = Body::from("1\n\n2\r\n 3 \n4444\n5");
+    let req = http::Request::builder()
+        .header(http::header::CONTENT_TYPE, "application/x-ndjson")
+        .body(body)
+        .unwrap();
+    let mut req = Request::from_hyper(req);
+
+    let mut parser = NdjsonParser::default();
+    parser.line_limit(3);
+    let mut stream = req.parse_ndjson::<u32>(&parser).unwrap();
+
+    assert_eq!(block_on(stream.try_next()).unwrap(), Some(1));
+    assert_eq!(block_on(stream.try_next()).unwrap(), Some(2));
+    assert_eq!(block_on(stream.try_next()).unwrap(), Some(3));
+    let err = block_on(stream.try_next()).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<BodyError>(),
+        Some(BodyError::LengthLimitExceeded)
+    ));
+    assert_eq!(block_on(stream.try_next()).unwrap(), Some(5));
+    assert_eq!(block_on(stream.try_next()).unwrap(), None);
+}
+
+#[test]
+fn ndjson_stream_split_line() {
+    use futures::executor::block_on;
+    use futures::stream::{self, TryStreamExt};
+
+    let chunks: Vec<Result<_, std::io::Error>> = vec![Ok("1\n12"), Ok("345"), Ok("6\n7\n")];
+    let req = http::Request::builder()
+        .header(http::header::CONTENT_TYPE, "application/x-ndjson")
+        .body(Body::wrap_stream(stream::iter(chunks)))
+        .unwrap();
+    let mut req = Request::from_hyper(req);
+
+    let mut parser = NdjsonParser::default();
+    parser.line_limit(3);
+    let mut stream = req.parse_ndjson::<u32>(&parser).unwrap();
+
+    assert_eq!(block_on(stream.try_next()).unwrap(), Some(1));
+    let err = block_on(stream.try_next()).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<BodyError>(),
+        Some(BodyError::LengthLimitExceeded)
+    ));
+    assert_eq!(block_on(stream.try_next()).unwrap(), Some(7));
+    assert_eq!(block_on(stream.try_next()).unwrap(), None);
 }
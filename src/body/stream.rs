@@ -0,0 +1,261 @@
+use super::BodyError;
+use crate::internal_prelude::*;
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use bytes::{Buf, Bytes};
+use futures::stream::Stream;
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::time::{Instant, Sleep};
+
+/// A request body stream with a total length limit and timeouts
+///
+/// Bytes are yielded as received, without decoding `Content-Encoding`.
+/// The default length limit is 8 MiB, which suits moderate uploads;
+/// set [`BodyStream::length_limit`] explicitly for larger ones.
+pub struct BodyStream {
+    body: Body,
+    length_limit: usize,
+    idle_timeout: Option<Duration>,
+    min_data_rate: Option<MinDataRate>,
+    received: usize,
+    started: Option<Instant>,
+    last_active: Option<Instant>,
+    timer: Option<Pin<Box<Sleep>>>,
+    chunk: Bytes,
+    done: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct MinDataRate {
+    bytes_per_second: u64,
+    grace_period: Duration,
+}
+
+impl BodyStream {
+    const DEFAULT_LENGTH_LIMIT: usize = 8 * 1024 * 1024;
+
+    pub fn new(body: Body) -> Self {
+        Self {
+            body,
+            length_limit: Self::DEFAULT_LENGTH_LIMIT,
+            idle_timeout: None,
+            min_data_rate: None,
+            received: 0,
+            started: None,
+            last_active: None,
+            timer: None,
+            chunk: Bytes::new(),
+            done: false,
+        }
+    }
+
+    pub fn length_limit(&mut self, limit: usize) {
+        self.length_limit = limit;
+    }
+
+    /// Fails the stream if no data is received within `timeout`.
+    pub fn idle_timeout(&mut self, timeout: Duration) {
+        self.idle_timeout = Some(timeout);
+    }
+
+    /// Fails the stream if the average data rate drops below `bytes_per_second`
+    /// after `grace_period`.
+    pub fn min_data_rate(&mut self, bytes_per_second: u64, grace_period: Duration) {
+        self.min_data_rate = Some(MinDataRate {
+            bytes_per_second,
+            grace_period,
+        });
+    }
+
+    /// Returns the number of bytes received so far.
+    pub fn received(&self) -> usize {
+        self.received
+    }
+
+    fn deadline(&self, now: Instant) -> Option<Instant> {
+        let idle = self
+            .idle_timeout
+            .map(|t| self.last_active.unwrap_or(now) + t);
+
+        let rate = self
+            .min_data_rate
+            .filter(|r| r.bytes_per_second > 0)
+            .and_then(|r| {
+                let secs = self.received as f64 / r.bytes_per_second as f64;
+                let expected = Duration::from_secs_f64(secs).max(r.grace_period);
+                self.started.unwrap_or(now).checked_add(expected)
+            });
+
+        match (idle, rate) {
+            (Some(lhs), Some(rhs)) => Some(lhs.min(rhs)),
+            (lhs, rhs) => lhs.or(rhs),
+        }
+    }
+
+    fn reset_timer(&mut self, now: Instant) {
+        let deadline = match self.deadline(now) {
+            Some(d) => d,
+            None => return,
+        };
+        match self.timer.as_mut() {
+            Some(timer) => timer.as_mut().reset(deadline),
+            None => self.timer = Some(Box::pin(tokio::time::sleep_until(deadline))),
+        }
+    }
+
+    fn fail(&mut self, err: BodyError) -> Poll<Option<Result<Bytes>>> {
+        self.done = true;
+        self.timer = None;
+        Poll::Ready(Some(Err(err.into())))
+    }
+}
+
+impl Stream for BodyStream {
+    type Item = Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        if this.done {
+            return Poll::Ready(None);
+        }
+
+        if this.started.is_none() {
+            let now = Instant::now();
+            this.started = Some(now);
+            this.last_active = Some(now);
+            this.reset_timer(now);
+        }
+
+        match Pin::new(&mut this.body).poll_next(cx) {
+            Poll::Ready(Some(Ok(bytes))) => {
+                this.received = match this.received.checked_add(bytes.len()) {
+                    Some(t) if t <= this.length_limit => t,
+                    _ => return this.fail(BodyError::LengthLimitExceeded),
+                };
+                let now = Instant::now();
+                this.last_active = Some(now);
+                this.reset_timer(now);
+                Poll::Ready(Some(Ok(bytes)))
+            }
+            Poll::Ready(Some(Err(e))) => {
+                this.done = true;
+                Poll::Ready(Some(Err(e.into())))
+            }
+            Poll::Ready(None) => {
+                this.done = true;
+                this.timer = None;
+                Poll::Ready(None)
+            }
+            Poll::Pending => {
+                if let Some(timer) = this.timer.as_mut() {
+                    if timer.as_mut().poll(cx).is_ready() {
+                        return this.fail(BodyError::Timeout);
+                    }
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl AsyncRead for BodyStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        while this.chunk.is_empty() {
+            match futures::ready!(Pin::new(&mut *this).poll_next(cx)) {
+                Some(Ok(bytes)) => this.chunk = bytes,
+                Some(Err(e)) => return Poll::Ready(Err(io::Error::other(e))),
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+
+        let len = this.chunk.len().min(buf.remaining());
+        buf.put_slice(&this.chunk[..len]);
+        this.chunk.advance(len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn idle_timeout() {
+    use futures::stream::StreamExt;
+
+    let (mut tx, body) = Body::channel();
+    let mut stream = BodyStream::new(body);
+    stream.idle_timeout(Duration::from_millis(20));
+
+    tx.send_data(Bytes::from_static(b"hello")).await.unwrap();
+    assert_eq!(stream.next().await.unwrap().unwrap(), "hello");
+
+    let err = stream.next().await.unwrap().unwrap_err();
+    assert!(matches!(err.downcast_ref(), Some(BodyError::Timeout)));
+    assert!(stream.next().await.is_none());
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn min_data_rate() {
+    use futures::stream::StreamExt;
+
+    let (mut tx, body) = Body::channel();
+    let mut stream = BodyStream::new(body);
+    stream.min_data_rate(1000, Duration::from_millis(50));
+
+    tx.send_data(Bytes::from_static(b"hello")).await.unwrap();
+    assert_eq!(stream.next().await.unwrap().unwrap(), "hello");
+
+    let start = Instant::now();
+    let err = stream.next().await.unwrap().unwrap_err();
+    assert!(matches!(err.downcast_ref(), Some(BodyError::Timeout)));
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn length_limit() {
+    use futures::stream::StreamExt;
+
+    let chunks: Vec<Result<_, io::Error>> = vec![Ok("hello"), Ok(" world")];
+    let mut stream = BodyStream::new(Body::wrap_stream(futures::stream::iter(chunks)));
+    stream.length_limit(8);
+
+    assert_eq!(stream.next().await.unwrap().unwrap(), "hello");
+    let err = stream.next().await.unwrap().unwrap_err();
+    assert!(matches!(
+        err.downcast_ref(),
+        Some(BodyError::LengthLimitExceeded)
+    ));
+    assert!(stream.next().await.is_none());
+    assert_eq!(stream.received(), 5);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn async_read() {
+    let chunks: Vec<Result<_, io::Error>> = vec![Ok("hello"), Ok(" "), Ok("world")];
+    let mut stream = BodyStream::new(Body::wrap_stream(futures::stream::iter(chunks)));
+
+    let mut buf = Vec::new();
+    tokio::io::copy(&mut stream, &mut buf).await.unwrap();
+    assert_eq!(buf, b"hello world");
+
+    let chunks: Vec<Result<_, io::Error>> = vec![Ok("hello"), Ok(" world")];
+    let mut stream = BodyStream::new(Body::wrap_stream(futures::stream::iter(chunks)));
+    stream.length_limit(8);
+
+    let mut buf = Vec::new();
+    let err = tokio::io::copy(&mut stream, &mut buf).await.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::Other);
+    assert_eq!(buf, b"hello");
+}
@@ -1,7 +1,13 @@
 pub use anyhow::{Error, Result};
 
+use crate::body::BodyError;
+use crate::extract::Rejection;
 use crate::http::{Body, StatusCode};
 use crate::response::Response;
+use crate::state::StateError;
+use crate::validate::ValidationErrors;
+
+use std::any::Any;
 
 pub trait CatchExt {
     type Value;
@@ -54,3 +60,91 @@ impl From<StatusError> for Response {
         Response::new(e.status, body)
     }
 }
+
+#[derive(Debug, thiserror::Error)]
+#[error("PanicError: {}", .message)]
+pub struct PanicError {
+    message: String,
+}
+
+impl PanicError {
+    pub fn from_payload(payload: &(dyn Any + Send)) -> Self {
+        let message = if let Some(s) = payload.downcast_ref::<&'static str>() {
+            (*s).to_owned()
+        } else if let Some(s) = payload.downcast_ref::<String>() {
+            s.clone()
+        } else {
+            "Box<dyn Any>".to_owned()
+        };
+        Self { message }
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl From<PanicError> for Response {
+    fn from(_: PanicError) -> Self {
+        StatusError::new(StatusCode::INTERNAL_SERVER_ERROR).into()
+    }
+}
+
+/// Converts an error returned by the root handler into a response.
+///
+/// The errors of this crate are mapped to their statuses. Other errors become 500.
+/// Server errors are logged at the error level and client errors at the debug level.
+pub fn error_response(err: Error) -> Response {
+    let err = match err.downcast::<Rejection>() {
+        Ok(e) => return e.into(),
+        Err(err) => err,
+    };
+    let err = match err.downcast::<ValidationErrors>() {
+        Ok(e) => {
+            tracing::debug!("validation failed: {:?}", e);
+            return e.into();
+        }
+        Err(err) => err,
+    };
+    let err = match err.downcast::<StateError>() {
+        Ok(e) => return e.into(),
+        Err(err) => err,
+    };
+    let err = match err.downcast::<PanicError>() {
+        Ok(e) => {
+            tracing::error!("handler panicked: {}", e.message());
+            return e.into();
+        }
+        Err(err) => err,
+    };
+
+    let status = if let Some(e) = err.downcast_ref::<StatusError>() {
+        e.status
+    } else if let Some(e) = err.downcast_ref::<BodyError>() {
+        e.status()
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+
+    if status.is_server_error() {
+        tracing::error!("unhandled error: {:?}", err);
+    } else {
+        tracing::debug!("unhandled error: {:?}", err);
+    }
+    StatusError::new(status).into()
+}
+
+#[test]
+fn error_status() {
+    let status = |err: Error| error_response(err).status();
+
+    assert_eq!(status(StatusError::NOT_FOUND.into()), StatusCode::NOT_FOUND);
+    assert_eq!(
+        status(BodyError::LengthLimitExceeded.into()),
+        StatusCode::PAYLOAD_TOO_LARGE
+    );
+    assert_eq!(
+        status(anyhow::anyhow!("oops")),
+        StatusCode::INTERNAL_SERVER_ERROR
+    );
+}
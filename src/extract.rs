@@ -0,0 +1,693 @@
+use crate::body::{BodyError, FormParser, JsonExt};
+use crate::error::StatusError;
+use crate::http::{self, HeaderMap, Method, StatusCode, Uri};
+use crate::internal_prelude::*;
+use crate::response::Json;
+use crate::router::SimpleRouterExt;
+use crate::state::{StateError, StateScope};
+use crate::validate::{validate, Validate, ValidationErrors};
+
+use std::mem;
+use std::ops;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use serde::de::DeserializeOwned;
+
+mod params;
+
+use self::params::ParamsDeserializer;
+
+/// Extracts a value from a request
+///
+/// Extractors run in the order of handler arguments.
+/// An extractor consuming the body should be the last one.
+#[async_trait]
+pub trait FromRequest: Sized {
+    async fn from_request(req: &mut Request) -> Result<Self, Rejection>;
+
+    /// Reports the states required by this extractor.
+    fn check_state(scope: &mut StateScope) {
+        let _ = scope;
+    }
+}
+
+/// Extracts a value from a request without consuming the body
+pub trait FromRequestParts: Sized {
+    fn from_request_parts(req: &Request) -> Result<Self, Rejection>;
+
+    /// Reports the states required by this extractor.
+    fn check_state(scope: &mut StateScope) {
+        let _ = scope;
+    }
+}
+
+#[async_trait]
+impl<T> FromRequest for T
+where
+    T: FromRequestParts + Send,
+{
+    async fn from_request(req: &mut Request) -> Result<Self, Rejection> {
+        T::from_request_parts(req)
+    }
+
+    fn check_state(scope: &mut StateScope) {
+        <T as FromRequestParts>::check_state(scope)
+    }
+}
+
+/// The error of an extractor
+///
+/// Handlers respond to client errors directly and return server errors,
+/// which are rendered by the error responder of the server.
+#[derive(Debug, thiserror::Error)]
+#[error("Rejection: {}: {}", .status.as_str(), .error)]
+pub struct Rejection {
+    status: StatusCode,
+    error: Error,
+}
+
+impl Rejection {
+    pub fn new(status: StatusCode, error: impl Into<Error>) -> Self {
+        Self {
+            status,
+            error: error.into(),
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn error(&self) -> &Error {
+        &self.error
+    }
+
+    /// Responds to client errors directly and passes server errors on,
+    /// so that they reach the error responder of the server.
+    pub(crate) fn into_result(self) -> Result<Response> {
+        if self.status.is_server_error() {
+            Err(self.into())
+        } else {
+            Ok(self.into())
+        }
+    }
+}
+
+impl From<Error> for Rejection {
+    fn from(error: Error) -> Self {
+        let status = if let Some(e) = error.downcast_ref::<BodyError>() {
+            e.status()
+        } else if let Some(e) = error.downcast_ref::<StatusError>() {
+            e.status
+        } else if error.is::<StateError>() {
+            StatusCode::INTERNAL_SERVER_ERROR
+        } else if error.is::<ValidationErrors>() {
+            StatusCode::UNPROCESSABLE_ENTITY
+        } else {
+            StatusCode::BAD_REQUEST
+        };
+        Self { status, error }
+    }
+}
+
+impl From<BodyError> for Rejection {
+    fn from(e: BodyError) -> Self {
+        Self::new(e.status(), e)
+    }
+}
+
+impl From<StatusError> for Rejection {
+    fn from(e: StatusError) -> Self {
+        Self::new(e.status, e)
+    }
+}
+
+impl From<ValidationErrors> for Rejection {
+    fn from(e: ValidationErrors) -> Self {
+        Self::new(StatusCode::UNPROCESSABLE_ENTITY, e)
+    }
+}
+
+impl From<Rejection> for Response {
+    fn from(e: Rejection) -> Self {
+        if e.status.is_server_error() {
+            tracing::error!("request rejected: {:?}", e);
+        } else {
+            tracing::debug!("request rejected: {}", e);
+        }
+        match e.error.downcast::<ValidationErrors>() {
+            Ok(errors) => errors.into(),
+            Err(_) => StatusError::new(e.status).into(),
+        }
+    }
+}
+
+macro_rules! impl_from_request_tuple {
+    ($($ty:ident $id:ident),*) => {
+        #[async_trait]
+        impl<$($ty,)*> FromRequest for ($($ty,)*)
+        where
+            $($ty: FromRequest + Send,)*
+        {
+            #[allow(unused_variables)]
+            async fn from_request(req: &mut Request) -> Result<Self, Rejection> {
+                $(let $id = $ty::from_request(req).await?;)*
+                Ok(($($id,)*))
+            }
+
+            #[allow(unused_variables)]
+            fn check_state(scope: &mut StateScope) {
+                $($ty::check_state(scope);)*
+            }
+        }
+    };
+}
+
+impl_from_request_tuple!();
+impl_from_request_tuple!(A0 a0);
+impl_from_request_tuple!(A0 a0, A1 a1);
+impl_from_request_tuple!(A0 a0, A1 a1, A2 a2);
+impl_from_request_tuple!(A0 a0, A1 a1, A2 a2, A3 a3);
+impl_from_request_tuple!(A0 a0, A1 a1, A2 a2, A3 a3, A4 a4);
+impl_from_request_tuple!(A0 a0, A1 a1, A2 a2, A3 a3, A4 a4, A5 a5);
+impl_from_request_tuple!(A0 a0, A1 a1, A2 a2, A3 a3, A4 a4, A5 a5, A6 a6);
+impl_from_request_tuple!(A0 a0, A1 a1, A2 a2, A3 a3, A4 a4, A5 a5, A6 a6, A7 a7);
+impl_from_request_tuple!(A0 a0, A1 a1, A2 a2, A3 a3, A4 a4, A5 a5, A6 a6, A7 a7, A8 a8);
+impl_from_request_tuple!(A0 a0, A1 a1, A2 a2, A3 a3, A4 a4, A5 a5, A6 a6, A7 a7, A8 a8, A9 a9);
+impl_from_request_tuple!(A0 a0, A1 a1, A2 a2, A3 a3, A4 a4, A5 a5, A6 a6, A7 a7, A8 a8, A9 a9, A10 a10);
+impl_from_request_tuple!(A0 a0, A1 a1, A2 a2, A3 a3, A4 a4, A5 a5, A6 a6, A7 a7, A8 a8, A9 a9, A10 a10, A11 a11);
+
+/// Takes the whole request, leaving an empty one for later extractors
+#[async_trait]
+impl FromRequest for Request {
+    async fn from_request(req: &mut Request) -> Result<Self, Rejection> {
+        Ok(mem::replace(
+            req,
+            Request::from_hyper(HyperRequest::default()),
+        ))
+    }
+}
+
+#[async_trait]
+impl<T> FromRequest for Json<T>
+where
+    T: DeserializeOwned + Send,
+{
+    async fn from_request(req: &mut Request) -> Result<Self, Rejection> {
+        Ok(Json(req.json::<T>().await?))
+    }
+}
+
+/// Deserializes `application/x-www-form-urlencoded` bodies
+///
+/// The body is parsed by the [`FormParser`] in the request extensions, or the default one.
+pub struct Form<T>(pub T);
+
+#[async_trait]
+impl<T> FromRequest for Form<T>
+where
+    T: DeserializeOwned + Send,
+{
+    async fn from_request(req: &mut Request) -> Result<Self, Rejection> {
+        let parser = match req.extensions().get::<FormParser>() {
+            Some(p) => p.clone(),
+            None => FormParser::default(),
+        };
+        Ok(Form(parser.parse(req).await?))
+    }
+}
+
+/// Deserializes the query string
+pub struct Query<T>(pub T);
+
+impl<T> FromRequestParts for Query<T>
+where
+    T: DeserializeOwned,
+{
+    fn from_request_parts(req: &Request) -> Result<Self, Rejection> {
+        let query = req.uri().query().unwrap_or("");
+        match serde_urlencoded::from_str(query) {
+            Ok(value) => Ok(Query(value)),
+            Err(e) => Err(Rejection::new(StatusCode::BAD_REQUEST, e)),
+        }
+    }
+}
+
+/// Deserializes the path params captured by [`SimpleRouter`](crate::router::SimpleRouter)
+///
+/// Params are percent-decoded. `T` can be a struct, a map,
+/// a tuple of params in order, or a single scalar.
+pub struct Path<T>(pub T);
+
+impl<T> FromRequestParts for Path<T>
+where
+    T: DeserializeOwned,
+{
+    fn from_request_parts(req: &Request) -> Result<Self, Rejection> {
+        let capture = match req.capture() {
+            Some(c) => c,
+            None => {
+                let msg = "path params are not captured by a router";
+                return Err(Rejection::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    anyhow::anyhow!(msg),
+                ));
+            }
+        };
+
+        let params = match params::decode(capture.params()) {
+            Ok(p) => p,
+            Err(e) => return Err(Rejection::new(StatusCode::BAD_REQUEST, e)),
+        };
+
+        match T::deserialize(ParamsDeserializer::new(&params)) {
+            Ok(value) => Ok(Path(value)),
+            Err(e) => Err(Rejection::new(StatusCode::BAD_REQUEST, e)),
+        }
+    }
+}
+
+/// Validates the value of another extractor
+///
+/// Violations are rejected with `422 Unprocessable Entity`,
+/// the same as [`JsonExt::valid_json`].
+pub struct Valid<E>(pub E);
+
+#[async_trait]
+impl<E> FromRequest for Valid<E>
+where
+    E: FromRequest + Validate + Send,
+{
+    async fn from_request(req: &mut Request) -> Result<Self, Rejection> {
+        let value = E::from_request(req).await?;
+        validate(&value)?;
+        Ok(Valid(value))
+    }
+
+    fn check_state(scope: &mut StateScope) {
+        E::check_state(scope)
+    }
+}
+
+macro_rules! impl_validate_wrapper {
+    ($($ty:ident),*) => {
+        $(
+            impl<T: Validate> Validate for $ty<T> {
+                fn validate(&self, errors: &mut ValidationErrors) {
+                    self.0.validate(errors)
+                }
+            }
+        )*
+    };
+}
+
+impl_validate_wrapper!(Json, Form, Query, Path);
+
+/// Injects the state provided by [`WithState`](crate::handler::WithState)
+/// or [`WithRequestState`](crate::handler::WithRequestState)
+pub struct State<S>(pub Arc<S>);
+
+impl<S> FromRequestParts for State<S>
+where
+    S: Send + Sync + 'static,
+{
+    fn from_request_parts(req: &Request) -> Result<Self, Rejection> {
+        match req.state::<S>() {
+            Some(s) => Ok(State(s)),
+            None => Err(Rejection::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                StateError::new::<S>().with_target::<Self>(),
+            )),
+        }
+    }
+
+    fn check_state(scope: &mut StateScope) {
+        scope.require::<S, Self>()
+    }
+}
+
+/// Injects the request-scoped value provided by [`RequestScope`](crate::scope::RequestScope)
+pub struct Scoped<T>(pub Arc<T>);
+
+impl<T> ops::Deref for Scoped<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[async_trait]
+impl<T> FromRequest for Scoped<T>
+where
+    T: Send + Sync + 'static,
+{
+    async fn from_request(req: &mut Request) -> Result<Self, Rejection> {
+        // a failing provider is a server error, not a bad request
+        match req.scoped::<T>().await {
+            Ok(value) => Ok(Scoped(value)),
+            Err(e) => match e.downcast::<Rejection>() {
+                Ok(rejection) => Err(rejection),
+                Err(e) => Err(Rejection::new(StatusCode::INTERNAL_SERVER_ERROR, e)),
+            },
+        }
+    }
+
+    fn check_state(scope: &mut StateScope) {
+        scope.require::<T, Self>()
+    }
+}
+
+/// The cookies sent by the client
+#[derive(Debug, Clone, Default)]
+pub struct Cookies {
+    pairs: Vec<(String, String)>,
+}
+
+impl Cookies {
+    pub fn parse(headers: &HeaderMap) -> Self {
+        let mut pairs = Vec::new();
+        for value in headers.get_all(http::header::COOKIE) {
+            let value = match value.to_str() {
+                Ok(s) => s,
+                Err(_) => continue,
+            };
+            for pair in value.split(';') {
+                if let Some((name, value)) = pair.split_once('=') {
+                    let value = value.trim();
+                    let value = value.trim_matches('"');
+                    pairs.push((name.trim().to_owned(), value.to_owned()));
+                }
+            }
+        }
+        Self { pairs }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.pairs
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> + '_ {
+        self.pairs.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+}
+
+impl FromRequestParts for Cookies {
+    fn from_request_parts(req: &Request) -> Result<Self, Rejection> {
+        Ok(Cookies::parse(req.headers()))
+    }
+}
+
+impl FromRequestParts for HeaderMap {
+    fn from_request_parts(req: &Request) -> Result<Self, Rejection> {
+        Ok(req.headers().clone())
+    }
+}
+
+impl FromRequestParts for Method {
+    fn from_request_parts(req: &Request) -> Result<Self, Rejection> {
+        Ok(req.method().clone())
+    }
+}
+
+impl FromRequestParts for Uri {
+    fn from_request_parts(req: &Request) -> Result<Self, Rejection> {
+        Ok(req.uri().clone())
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn extractors() {
+    use crate::functional::handler;
+    use crate::router::SimpleRouter;
+
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Deserialize)]
+    struct Page {
+        page: u32,
+    }
+
+    #[derive(Deserialize, Serialize)]
+    struct Post {
+        title: String,
+    }
+
+    async fn number(Path(n): Path<u64>) -> String {
+        n.to_string()
+    }
+
+    async fn text(Path(s): Path<String>) -> String {
+        s
+    }
+
+    async fn pair(Path((a, b)): Path<(String, u32)>) -> String {
+        format!("{} {}", a, b)
+    }
+
+    async fn query(Query(p): Query<Page>) -> String {
+        p.page.to_string()
+    }
+
+    async fn cookies(cookies: Cookies) -> String {
+        cookies.get("user").unwrap_or("").to_owned()
+    }
+
+    async fn form(Form(post): Form<Post>) -> String {
+        post.title
+    }
+
+    async fn json(Json(post): Json<Post>) -> Json<Post> {
+        Json(post)
+    }
+
+    async fn body_last(Query(p): Query<Page>, req: Request) -> String {
+        format!("{} {}", p.page, req.uri().path())
+    }
+
+    async fn body_first(req: Request, Query(p): Query<Page>) -> String {
+        format!("{} {}", p.page, req.uri().path())
+    }
+
+    let mut router = SimpleRouter::new();
+    router.at("/a/:n").get(handler(number).boxed());
+    router.at("/b/:s").get(handler(text).boxed());
+    router.at("/c/:a/:b").get(handler(pair).boxed());
+    router.at("/query").get(handler(query).boxed());
+    router.at("/cookies").get(handler(cookies).boxed());
+    router.at("/form").post(handler(form).boxed());
+    router.at("/json").post(handler(json).boxed());
+    router.at("/last").get(handler(body_last).boxed());
+    router.at("/first").get(handler(body_first).boxed());
+
+    async fn call(
+        router: &SimpleRouter,
+        req: http::request::Builder,
+        body: impl Into<Body>,
+    ) -> (StatusCode, String) {
+        let req = Request::from_hyper(req.body(body.into()).unwrap());
+        let res = router.handle(req).await.unwrap().into_hyper();
+        let status = res.status();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    let get = |uri: &str| http::Request::builder().uri(uri);
+    let post = |uri: &str, mime: &str| {
+        http::Request::builder()
+            .method(Method::POST)
+            .uri(uri)
+            .header(http::header::CONTENT_TYPE, mime)
+    };
+    let ok = |s: &str| (StatusCode::OK, s.to_owned());
+
+    assert_eq!(call(&router, get("/a/42"), "").await, ok("42"));
+    assert_eq!(
+        call(&router, get("/a/x"), "").await.0,
+        StatusCode::BAD_REQUEST
+    );
+    assert_eq!(
+        call(&router, get("/b/hello%20world"), "").await,
+        ok("hello world")
+    );
+    assert_eq!(call(&router, get("/c/x%2Fy/7"), "").await, ok("x/y 7"));
+
+    assert_eq!(call(&router, get("/query?page=3"), "").await, ok("3"));
+    assert_eq!(
+        call(&router, get("/query"), "").await.0,
+        StatusCode::BAD_REQUEST
+    );
+
+    let req = get("/cookies").header(http::header::COOKIE, "theme=dark; user=\"alice\"");
+    assert_eq!(call(&router, req, "").await, ok("alice"));
+
+    let form_mime = "application/x-www-form-urlencoded";
+    let req = post("/form", form_mime);
+    assert_eq!(
+        call(&router, req, "title=hello+world").await,
+        ok("hello world")
+    );
+    let req = post("/form", "text/plain");
+    let status = call(&router, req, "title=hello").await.0;
+    assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+    let req = post("/json", "application/json");
+    assert_eq!(
+        call(&router, req, r#"{"title":"x"}"#).await,
+        ok(r#"{"title":"x"}"#)
+    );
+    let req = post("/json", "application/json");
+    assert_eq!(call(&router, req, "{").await.0, StatusCode::BAD_REQUEST);
+    let req = post("/json", "application/json");
+    let large = format!(r#"{{"title":"{}"}}"#, "x".repeat(64 * 1024));
+    assert_eq!(
+        call(&router, req, large).await.0,
+        StatusCode::PAYLOAD_TOO_LARGE
+    );
+
+    // An extractor taking the body leaves nothing for the extractors after it.
+    assert_eq!(call(&router, get("/last?page=1"), "").await, ok("1 /last"));
+    assert_eq!(
+        call(&router, get("/first?page=1"), "").await.0,
+        StatusCode::BAD_REQUEST
+    );
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn valid_extractors() {
+    use crate::functional::handler;
+
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    struct Page {
+        page: u32,
+    }
+
+    impl Validate for Page {
+        fn validate(&self, errors: &mut ValidationErrors) {
+            errors.check(self.page >= 1, "page", "must be at least 1");
+        }
+    }
+
+    async fn query(Valid(Query(p)): Valid<Query<Page>>) -> String {
+        p.page.to_string()
+    }
+
+    async fn form(Valid(Form(p)): Valid<Form<Page>>) -> String {
+        p.page.to_string()
+    }
+
+    let form_mime = "application/x-www-form-urlencoded";
+    let cases = [
+        (handler(query).boxed(), "/?page=2", None, StatusCode::OK),
+        (
+            handler(query).boxed(),
+            "/?page=0",
+            None,
+            StatusCode::UNPROCESSABLE_ENTITY,
+        ),
+        (
+            handler(query).boxed(),
+            "/?page=x",
+            None,
+            StatusCode::BAD_REQUEST,
+        ),
+        (handler(form).boxed(), "/", Some("page=2"), StatusCode::OK),
+        (
+            handler(form).boxed(),
+            "/",
+            Some("page=0"),
+            StatusCode::UNPROCESSABLE_ENTITY,
+        ),
+    ];
+    for (h, uri, body, status) in cases.iter() {
+        let mut req = http::Request::builder().uri(*uri);
+        if body.is_some() {
+            req = req.header(http::header::CONTENT_TYPE, form_mime);
+        }
+        let req = req.body(Body::from(body.unwrap_or(""))).unwrap();
+        let res = h.handle(Request::from_hyper(req)).await.unwrap();
+        assert_eq!(res.status(), *status, "{}", uri);
+
+        if *status == StatusCode::UNPROCESSABLE_ENTITY {
+            let body = hyper::body::to_bytes(res.into_hyper().into_body()).await;
+            let body: serde_json::Value = serde_json::from_slice(&body.unwrap()).unwrap();
+            assert_eq!(body["violations"][0]["field"], "page");
+        }
+    }
+}
+
+#[test]
+fn form_length_limit() {
+    use futures::executor::block_on;
+
+    let mut parser = FormParser::default();
+    parser.length_limit(4);
+    let req = http::Request::builder()
+        .header(
+            http::header::CONTENT_TYPE,
+            "application/x-www-form-urlencoded",
+        )
+        .body(Body::from("a=12345"))
+        .unwrap();
+    let mut req = Request::from_hyper(req);
+    req.extensions_mut().insert(parser);
+
+    let rejection = match block_on(Form::<Vec<(String, String)>>::from_request(&mut req)) {
+        Ok(_) => panic!("form should be rejected"),
+        Err(r) => r,
+    };
+    assert_eq!(rejection.status(), StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+#[test]
+fn rejection_status() {
+    let rejection = Rejection::from(anyhow::anyhow!("bad"));
+    assert_eq!(rejection.status(), StatusCode::BAD_REQUEST);
+
+    let rejection = Rejection::from(Error::from(BodyError::Timeout));
+    assert_eq!(rejection.status(), StatusCode::REQUEST_TIMEOUT);
+
+    let rejection = Rejection::from(StatusError::new(StatusCode::FORBIDDEN));
+    assert_eq!(rejection.status(), StatusCode::FORBIDDEN);
+
+    let req = Request::from_hyper(HyperRequest::default());
+    let rejection = match State::<u32>::from_request_parts(&req) {
+        Ok(_) => panic!("state should be missing"),
+        Err(r) => r,
+    };
+    assert_eq!(rejection.status(), StatusCode::INTERNAL_SERVER_ERROR);
+}
+
+#[test]
+fn scoped_provider_error() {
+    use crate::error::error_response;
+    use crate::functional::handler;
+    use crate::scope::{RequestScope, RequestScoped};
+
+    use futures::executor::block_on;
+
+    struct Pool;
+
+    impl RequestScoped for Pool {}
+
+    async fn query(_: Scoped<Pool>) {}
+
+    let mut scope = RequestScope::new();
+    scope.provide(|_| async { Err::<Pool, _>(anyhow::anyhow!("pool exhausted")) });
+    let h = handler(query).wrap(scope);
+
+    let req = Request::from_hyper(HyperRequest::default());
+    let res = match block_on(h.handle(req)) {
+        Ok(res) => res,
+        Err(err) => error_response(err),
+    };
+    assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+}
@@ -0,0 +1,328 @@
+use std::borrow::Cow;
+use std::fmt;
+
+use percent_encoding::percent_decode_str;
+use serde::de::{self, DeserializeSeed, IntoDeserializer, Visitor};
+use serde::forward_to_deserialize_any;
+
+#[derive(Debug, thiserror::Error)]
+#[error("ParamsError: {}", .0)]
+pub struct ParamsError(String);
+
+impl de::Error for ParamsError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        ParamsError(msg.to_string())
+    }
+}
+
+/// Percent-decodes the captured params.
+pub(crate) fn decode<'p>(
+    params: impl Iterator<Item = (&'p str, &'p str)>,
+) -> Result<Vec<(&'p str, Cow<'p, str>)>, ParamsError> {
+    params
+        .map(
+            |(name, value)| match percent_decode_str(value).decode_utf8() {
+                Ok(value) => Ok((name, value)),
+                Err(_) => Err(ParamsError(format!("param {:?} is not valid UTF-8", name))),
+            },
+        )
+        .collect()
+}
+
+/// Deserializes path params into a struct, a map, a tuple or a single scalar
+pub(crate) struct ParamsDeserializer<'a, 'p> {
+    params: &'a [(&'p str, Cow<'p, str>)],
+}
+
+impl<'a, 'p> ParamsDeserializer<'a, 'p> {
+    pub(crate) fn new(params: &'a [(&'p str, Cow<'p, str>)]) -> Self {
+        Self { params }
+    }
+
+    fn single(&self) -> Result<ValueDeserializer<'a>, ParamsError> {
+        match self.params {
+            [(_, value)] => Ok(ValueDeserializer(value)),
+            _ => Err(ParamsError(format!(
+                "expected 1 param, found {}",
+                self.params.len()
+            ))),
+        }
+    }
+}
+
+macro_rules! forward_to_single {
+    ($($method:ident)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+                self.single()?.$method(visitor)
+            }
+        )*
+    };
+}
+
+impl<'de, 'a, 'p> de::Deserializer<'de> for ParamsDeserializer<'a, 'p> {
+    type Error = ParamsError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.params.len() {
+            1 => self.single()?.deserialize_any(visitor),
+            _ => self.deserialize_map(visitor),
+        }
+    }
+
+    forward_to_single! {
+        deserialize_bool deserialize_i8 deserialize_i16 deserialize_i32 deserialize_i64
+        deserialize_u8 deserialize_u16 deserialize_u32 deserialize_u64
+        deserialize_f32 deserialize_f64 deserialize_char deserialize_str deserialize_string
+        deserialize_bytes deserialize_byte_buf deserialize_option deserialize_identifier
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_seq(ParamsSeq(self.params.iter()))
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        if self.params.len() != len {
+            return Err(ParamsError(format!(
+                "expected {} params, found {}",
+                len,
+                self.params.len()
+            )));
+        }
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.deserialize_tuple(len, visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_map(ParamsMap {
+            iter: self.params.iter(),
+            value: None,
+        })
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.single()?.deserialize_enum(name, variants, visitor)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_unit()
+    }
+}
+
+struct ParamsSeq<'a, 'p>(std::slice::Iter<'a, (&'p str, Cow<'p, str>)>);
+
+impl<'de, 'a, 'p> de::SeqAccess<'de> for ParamsSeq<'a, 'p> {
+    type Error = ParamsError;
+
+    fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>, Self::Error>
+    where
+        T: DeserializeSeed<'de>,
+    {
+        match self.0.next() {
+            Some((_, value)) => seed.deserialize(ValueDeserializer(value)).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.0.len())
+    }
+}
+
+struct ParamsMap<'a, 'p> {
+    iter: std::slice::Iter<'a, (&'p str, Cow<'p, str>)>,
+    value: Option<&'a str>,
+}
+
+impl<'de, 'a, 'p> de::MapAccess<'de> for ParamsMap<'a, 'p> {
+    type Error = ParamsError;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>, Self::Error>
+    where
+        K: DeserializeSeed<'de>,
+    {
+        match self.iter.next() {
+            Some((name, value)) => {
+                self.value = Some(value);
+                seed.deserialize(ValueDeserializer(name)).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value, Self::Error>
+    where
+        V: DeserializeSeed<'de>,
+    {
+        match self.value.take() {
+            Some(value) => seed.deserialize(ValueDeserializer(value)),
+            None => Err(ParamsError("value is missing".to_owned())),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.iter.len())
+    }
+}
+
+/// Deserializes a single decoded param
+struct ValueDeserializer<'a>(&'a str);
+
+macro_rules! parse_value {
+    ($($method:ident => $visit:ident,)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+                match self.0.parse() {
+                    Ok(v) => visitor.$visit(v),
+                    Err(e) => Err(ParamsError(format!("invalid value {:?}: {}", self.0, e))),
+                }
+            }
+        )*
+    };
+}
+
+impl<'de, 'a> de::Deserializer<'de> for ValueDeserializer<'a> {
+    type Error = ParamsError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_str(self.0)
+    }
+
+    parse_value! {
+        deserialize_bool => visit_bool,
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+        deserialize_char => visit_char,
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        let variant: de::value::StrDeserializer<'_, ParamsError> = self.0.into_deserializer();
+        visitor.visit_enum(variant)
+    }
+
+    forward_to_deserialize_any! {
+        str string bytes byte_buf unit unit_struct seq tuple
+        tuple_struct map struct identifier ignored_any
+        i128 u128
+    }
+}
+
+#[test]
+fn params() {
+    use serde::Deserialize;
+
+    fn from_params<T: de::DeserializeOwned>(params: &[(&str, &str)]) -> Result<T, ParamsError> {
+        let params = decode(params.iter().copied())?;
+        T::deserialize(ParamsDeserializer::new(&params))
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Post {
+        user: String,
+        pid: u64,
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    enum Kind {
+        Draft,
+        Published,
+    }
+
+    assert_eq!(from_params::<u64>(&[("n", "42")]).unwrap(), 42);
+    assert!(from_params::<u64>(&[("n", "x")]).is_err());
+    assert!(from_params::<u64>(&[("n", "1"), ("m", "2")]).is_err());
+    assert_eq!(
+        from_params::<String>(&[("s", "hello%20world")]).unwrap(),
+        "hello world"
+    );
+    assert_eq!(
+        from_params::<(String, u32)>(&[("a", "x%2Fy"), ("b", "7")]).unwrap(),
+        ("x/y".to_owned(), 7)
+    );
+    assert!(from_params::<(String, u32)>(&[("a", "x")]).is_err());
+    assert_eq!(
+        from_params::<Post>(&[("user", "alice"), ("pid", "3")]).unwrap(),
+        Post {
+            user: "alice".to_owned(),
+            pid: 3
+        }
+    );
+    assert_eq!(
+        from_params::<Kind>(&[("kind", "published")]).unwrap(),
+        Kind::Published
+    );
+    assert!(from_params::<Kind>(&[("kind", "other")]).is_err());
+    assert!(from_params::<String>(&[("s", "%FF")]).is_err());
+}
@@ -1,8 +1,10 @@
+use crate::error::PanicError;
+use crate::extract::FromRequest;
 use crate::handler::Handler;
 use crate::internal_prelude::*;
-use crate::state;
+use crate::scope;
+use crate::state::{self, StateScope};
 
-use std::any::type_name;
 use std::convert::TryInto;
 use std::marker::PhantomData;
 use std::sync::Arc;
@@ -18,11 +20,11 @@ mod sealed {
     }
 
     macro_rules! impl_async_fn {
-    (($($ty:tt,)+),($($id:tt,)+)) => {
-            impl<'a, $($ty,)+ F, U, O> AsyncFn<'a, ($($ty,)+)> for F
+    (($($ty:tt,)*),($($id:tt,)*)) => {
+            impl<'a, $($ty,)* F, U, O> AsyncFn<'a, ($($ty,)*)> for F
             where
-                $($ty:'a,)+
-                F: Fn($($ty,)+) -> U + Send + Sync + 'a,
+                $($ty:'a,)*
+                F: Fn($($ty,)*) -> U + Send + Sync + 'a,
                 U: Future<Output = O> + Send + 'a,
                 O: 'a,
             {
@@ -30,100 +32,187 @@ mod sealed {
 
                 type Output = O;
 
-                fn call<'t: 'a>(&'t self, ($($id,)+): ($($ty,)+)) -> Self::Future {
-                    (self)($($id,)+)
+                fn call<'t: 'a>(&'t self, ($($id,)*): ($($ty,)*)) -> Self::Future {
+                    (self)($($id,)*)
                 }
             }
         };
     }
 
+    impl_async_fn!((), ());
     impl_async_fn!((A0,), (a0,));
     impl_async_fn!((A0, A1,), (a0, a1,));
     impl_async_fn!((A0, A1, A2,), (a0, a1, a2,));
+    impl_async_fn!((A0, A1, A2, A3,), (a0, a1, a2, a3,));
+    impl_async_fn!((A0, A1, A2, A3, A4,), (a0, a1, a2, a3, a4,));
+    impl_async_fn!((A0, A1, A2, A3, A4, A5,), (a0, a1, a2, a3, a4, a5,));
+    impl_async_fn!((A0, A1, A2, A3, A4, A5, A6,), (a0, a1, a2, a3, a4, a5, a6,));
+    impl_async_fn!(
+        (A0, A1, A2, A3, A4, A5, A6, A7,),
+        (a0, a1, a2, a3, a4, a5, a6, a7,)
+    );
+    impl_async_fn!(
+        (A0, A1, A2, A3, A4, A5, A6, A7, A8,),
+        (a0, a1, a2, a3, a4, a5, a6, a7, a8,)
+    );
+    impl_async_fn!(
+        (A0, A1, A2, A3, A4, A5, A6, A7, A8, A9,),
+        (a0, a1, a2, a3, a4, a5, a6, a7, a8, a9,)
+    );
+    impl_async_fn!(
+        (A0, A1, A2, A3, A4, A5, A6, A7, A8, A9, A10,),
+        (a0, a1, a2, a3, a4, a5, a6, a7, a8, a9, a10,)
+    );
+    impl_async_fn!(
+        (A0, A1, A2, A3, A4, A5, A6, A7, A8, A9, A10, A11,),
+        (a0, a1, a2, a3, a4, a5, a6, a7, a8, a9, a10, a11,)
+    );
+    impl_async_fn!(
+        (A0, A1, A2, A3, A4, A5, A6, A7, A8, A9, A10, A11, A12,),
+        (a0, a1, a2, a3, a4, a5, a6, a7, a8, a9, a10, a11, a12,)
+    );
 }
 
 use self::sealed::AsyncFn;
 
-pub fn handler<F>(f: F) -> HandlerFn<F> {
-    HandlerFn { f }
+/// Creates a handler from an async function taking up to 12 extractors.
+///
+/// The extractors are inferred from the argument types,
+/// so the arguments of closures must be annotated, as in `handler(|req: Request| ..)`.
+/// Use [`request_handler`] for closures taking only the request.
+pub fn handler<F, T>(f: F) -> HandlerFn<F, T>
+where
+    F: for<'a> AsyncFn<'a, T>,
+{
+    HandlerFn {
+        f,
+        _marker: PhantomData,
+    }
 }
 
-pub struct HandlerFn<F> {
+/// Creates a handler from an async function taking the request,
+/// which infers the argument of closures like `request_handler(|_| async { .. })`.
+pub fn request_handler<F, U>(f: F) -> HandlerFn<F, (Request,)>
+where
+    F: Fn(Request) -> U + Send + Sync,
+    U: Future + Send,
+{
+    HandlerFn {
+        f,
+        _marker: PhantomData,
+    }
+}
+
+pub struct HandlerFn<F, T> {
     f: F,
+    _marker: PhantomData<fn(T)>,
 }
 
-impl<F, R> Handler for HandlerFn<F>
+impl<F, T, R> Handler for HandlerFn<F, T>
 where
-    F: for<'a> AsyncFn<'a, (Request,), Output = R>,
+    T: FromRequest + Send,
+    F: for<'a> AsyncFn<'a, T, Output = R>,
     R: TryInto<Response>,
     R::Error: Into<Error>,
 {
-    fn handle<'t, 'a>(&'t self, req: Request) -> BoxFuture<'a, Result<Response>>
+    fn handle<'t, 'a>(&'t self, mut req: Request) -> BoxFuture<'a, Result<Response>>
     where
         't: 'a,
         Self: 'a,
     {
         Box::pin(async move {
-            AsyncFn::call(&self.f, (req,))
+            let args = match T::from_request(&mut req).await {
+                Ok(args) => args,
+                Err(rejection) => return rejection.into_result(),
+            };
+            AsyncFn::call(&self.f, args)
                 .await
                 .try_into()
                 .map_err(Into::into)
         })
     }
+
+    fn check_state(&self, scope: &mut StateScope) {
+        T::check_state(scope)
+    }
 }
 
-impl<F> Clone for HandlerFn<F>
+impl<F, T> Clone for HandlerFn<F, T>
 where
     F: Clone,
 {
     fn clone(&self) -> Self {
-        Self { f: self.f.clone() }
+        Self {
+            f: self.f.clone(),
+            _marker: PhantomData,
+        }
     }
 }
 
-pub fn ref_handler<S, F>(f: F) -> RefHandlerFn<S, F> {
-    RefHandlerFn {
-        f,
-        _marker: PhantomData,
-    }
+/// Runs a synchronous handler on the blocking thread pool of tokio.
+///
+/// The states injected by enclosing [`WithState`](crate::handler::WithState)s
+/// are entered on the blocking thread. A panic is returned as [`PanicError`].
+pub fn blocking_handler<F>(f: F) -> BlockingHandlerFn<F> {
+    BlockingHandlerFn { f: Arc::new(f) }
 }
 
-pub struct RefHandlerFn<S, F> {
-    f: F,
-    _marker: PhantomData<fn(&S)>,
+pub struct BlockingHandlerFn<F> {
+    f: Arc<F>,
 }
 
-impl<S, F, R> Handler for RefHandlerFn<S, F>
+impl<F, R> Handler for BlockingHandlerFn<F>
 where
-    S: Send + Sync + 'static,
-    F: for<'a> AsyncFn<'a, (&'a S, Request), Output = R>,
-    R: TryInto<Response>,
+    F: Fn(Request) -> R + Send + Sync + 'static,
+    R: TryInto<Response> + Send + 'static,
     R::Error: Into<Error>,
 {
-    #[track_caller]
     fn handle<'t, 'a>(&'t self, req: Request) -> BoxFuture<'a, Result<Response>>
     where
         't: 'a,
         Self: 'a,
     {
-        let state = match state::inject::<S>() {
-            Some(s) => s,
-            None => panic!(
-                "failed to inject state <{}> for handler <{}>",
-                type_name::<S>(),
-                type_name::<F>(),
-            ),
-        };
+        let f = Arc::clone(&self.f);
+        let snapshot = state::snapshot();
         Box::pin(async move {
-            AsyncFn::call(&self.f, (&*state, req))
-                .await
-                .try_into()
-                .map_err(Into::into)
+            let task = tokio::task::spawn_blocking(move || snapshot.enter(|| f(req)));
+            match task.await {
+                Ok(ret) => ret.try_into().map_err(Into::into),
+                Err(e) if e.is_panic() => {
+                    let payload = e.into_panic();
+                    Err(PanicError::from_payload(&*payload).into())
+                }
+                Err(e) => Err(e.into()),
+            }
         })
     }
 }
 
-impl<S, F> Clone for RefHandlerFn<S, F>
+impl<F> Clone for BlockingHandlerFn<F> {
+    fn clone(&self) -> Self {
+        Self {
+            f: Arc::clone(&self.f),
+        }
+    }
+}
+
+/// Creates a handler from an async function taking a reference to the state of `S`
+/// followed by extractors.
+///
+/// The extractors are a type parameter, so explicit calls are written as `ref_handler::<S, _, _>`.
+pub fn ref_handler<S, F, T>(f: F) -> RefHandlerFn<S, F, T> {
+    RefHandlerFn {
+        f,
+        _marker: PhantomData,
+    }
+}
+
+pub struct RefHandlerFn<S, F, T> {
+    f: F,
+    _marker: PhantomData<fn(&S, T)>,
+}
+
+impl<S, F, T> Clone for RefHandlerFn<S, F, T>
 where
     F: Clone,
 {
@@ -135,49 +224,20 @@ where
     }
 }
 
-pub fn arc_handler<S, F>(f: F) -> ArcHandlerFn<S, F> {
+/// Like [`ref_handler`], with the state passed as an `Arc`.
+pub fn arc_handler<S, F, T>(f: F) -> ArcHandlerFn<S, F, T> {
     ArcHandlerFn {
         f,
         _marker: PhantomData,
     }
 }
 
-pub struct ArcHandlerFn<S, F> {
+pub struct ArcHandlerFn<S, F, T> {
     f: F,
-    _marker: PhantomData<fn(Arc<S>)>,
+    _marker: PhantomData<fn(Arc<S>, T)>,
 }
 
-impl<S, F, R> Handler for ArcHandlerFn<S, F>
-where
-    S: Send + Sync + 'static,
-    F: for<'a> AsyncFn<'a, (Arc<S>, Request), Output = R>,
-    R: TryInto<Response>,
-    R::Error: Into<Error>,
-{
-    #[track_caller]
-    fn handle<'t, 'a>(&'t self, req: Request) -> BoxFuture<'a, Result<Response>>
-    where
-        't: 'a,
-        Self: 'a,
-    {
-        let state = match state::inject::<S>() {
-            Some(s) => s,
-            None => panic!(
-                "failed to inject state <{}> for handler <{}>",
-                type_name::<S>(),
-                type_name::<F>(),
-            ),
-        };
-        Box::pin(async move {
-            AsyncFn::call(&self.f, (state, req))
-                .await
-                .try_into()
-                .map_err(Into::into)
-        })
-    }
-}
-
-impl<S, F> Clone for ArcHandlerFn<S, F>
+impl<S, F, T> Clone for ArcHandlerFn<S, F, T>
 where
     F: Clone,
 {
@@ -189,6 +249,88 @@ where
     }
 }
 
+macro_rules! impl_state_handler_fn {
+    ($($ty:ident $id:ident),*) => {
+        impl<S, F, R, $($ty,)*> Handler for RefHandlerFn<S, F, ($($ty,)*)>
+        where
+            S: Send + Sync + 'static,
+            F: for<'a> AsyncFn<'a, (&'a S, $($ty,)*), Output = R>,
+            ($($ty,)*): FromRequest + Send,
+            R: TryInto<Response>,
+            R::Error: Into<Error>,
+        {
+            fn handle<'t, 'a>(&'t self, mut req: Request) -> BoxFuture<'a, Result<Response>>
+            where
+                't: 'a,
+                Self: 'a,
+            {
+                Box::pin(async move {
+                    let state = scope::resolve_state::<S, F>(&req).await?;
+                    let ($($id,)*) = match <($($ty,)*)>::from_request(&mut req).await {
+                        Ok(args) => args,
+                        Err(rejection) => return rejection.into_result(),
+                    };
+                    AsyncFn::call(&self.f, (&*state, $($id,)*))
+                        .await
+                        .try_into()
+                        .map_err(Into::into)
+                })
+            }
+
+            fn check_state(&self, scope: &mut StateScope) {
+                scope.require::<S, F>();
+                <($($ty,)*)>::check_state(scope);
+            }
+        }
+
+        impl<S, F, R, $($ty,)*> Handler for ArcHandlerFn<S, F, ($($ty,)*)>
+        where
+            S: Send + Sync + 'static,
+            F: for<'a> AsyncFn<'a, (Arc<S>, $($ty,)*), Output = R>,
+            ($($ty,)*): FromRequest + Send,
+            R: TryInto<Response>,
+            R::Error: Into<Error>,
+        {
+            fn handle<'t, 'a>(&'t self, mut req: Request) -> BoxFuture<'a, Result<Response>>
+            where
+                't: 'a,
+                Self: 'a,
+            {
+                Box::pin(async move {
+                    let state = scope::resolve_state::<S, F>(&req).await?;
+                    let ($($id,)*) = match <($($ty,)*)>::from_request(&mut req).await {
+                        Ok(args) => args,
+                        Err(rejection) => return rejection.into_result(),
+                    };
+                    AsyncFn::call(&self.f, (state, $($id,)*))
+                        .await
+                        .try_into()
+                        .map_err(Into::into)
+                })
+            }
+
+            fn check_state(&self, scope: &mut StateScope) {
+                scope.require::<S, F>();
+                <($($ty,)*)>::check_state(scope);
+            }
+        }
+    };
+}
+
+impl_state_handler_fn!();
+impl_state_handler_fn!(A0 a0);
+impl_state_handler_fn!(A0 a0, A1 a1);
+impl_state_handler_fn!(A0 a0, A1 a1, A2 a2);
+impl_state_handler_fn!(A0 a0, A1 a1, A2 a2, A3 a3);
+impl_state_handler_fn!(A0 a0, A1 a1, A2 a2, A3 a3, A4 a4);
+impl_state_handler_fn!(A0 a0, A1 a1, A2 a2, A3 a3, A4 a4, A5 a5);
+impl_state_handler_fn!(A0 a0, A1 a1, A2 a2, A3 a3, A4 a4, A5 a5, A6 a6);
+impl_state_handler_fn!(A0 a0, A1 a1, A2 a2, A3 a3, A4 a4, A5 a5, A6 a6, A7 a7);
+impl_state_handler_fn!(A0 a0, A1 a1, A2 a2, A3 a3, A4 a4, A5 a5, A6 a6, A7 a7, A8 a8);
+impl_state_handler_fn!(A0 a0, A1 a1, A2 a2, A3 a3, A4 a4, A5 a5, A6 a6, A7 a7, A8 a8, A9 a9);
+impl_state_handler_fn!(A0 a0, A1 a1, A2 a2, A3 a3, A4 a4, A5 a5, A6 a6, A7 a7, A8 a8, A9 a9, A10 a10);
+impl_state_handler_fn!(A0 a0, A1 a1, A2 a2, A3 a3, A4 a4, A5 a5, A6 a6, A7 a7, A8 a8, A9 a9, A10 a10, A11 a11);
+
 pub fn middleware<F>(f: F) -> MiddlewareFn<F> {
     MiddlewareFn { f }
 }
@@ -197,9 +339,11 @@ pub struct MiddlewareFn<F> {
     f: F,
 }
 
-impl<F> Middleware for MiddlewareFn<F>
+impl<F, R> Middleware for MiddlewareFn<F>
 where
-    F: for<'a> AsyncFn<'a, (Request, &'a dyn Handler), Output = Result<Response>>,
+    F: for<'a> AsyncFn<'a, (Request, &'a dyn Handler), Output = R>,
+    R: TryInto<Response>,
+    R::Error: Into<Error>,
 {
     fn handle<'t, 'n, 'a>(
         &'t self,
@@ -211,7 +355,12 @@ where
         'n: 'a,
         Self: 'a,
     {
-        Box::pin(AsyncFn::call(&self.f, (req, next)))
+        Box::pin(async move {
+            AsyncFn::call(&self.f, (req, next))
+                .await
+                .try_into()
+                .map_err(Into::into)
+        })
     }
 }
 
@@ -240,10 +389,12 @@ pub struct RefMiddlewareFn<S, F> {
     _marker: PhantomData<fn(&S)>,
 }
 
-impl<S, F> Middleware for RefMiddlewareFn<S, F>
+impl<S, F, R> Middleware for RefMiddlewareFn<S, F>
 where
     S: Send + Sync + 'static,
-    F: for<'a> AsyncFn<'a, (&'a S, Request, &'a dyn Handler), Output = Result<Response>>,
+    F: for<'a> AsyncFn<'a, (&'a S, Request, &'a dyn Handler), Output = R>,
+    R: TryInto<Response>,
+    R::Error: Into<Error>,
 {
     fn handle<'t, 'n, 'a>(
         &'t self,
@@ -255,15 +406,18 @@ where
         'n: 'a,
         Self: 'a,
     {
-        let state = match state::inject::<S>() {
-            Some(s) => s,
-            None => panic!(
-                "failed to inject state <{}> for middleware <{}>",
-                type_name::<S>(),
-                type_name::<F>(),
-            ),
-        };
-        Box::pin(async move { AsyncFn::call(&self.f, (&*state, req, next)).await })
+        Box::pin(async move {
+            let state = scope::resolve_state::<S, F>(&req).await?;
+            AsyncFn::call(&self.f, (&*state, req, next))
+                .await
+                .try_into()
+                .map_err(Into::into)
+        })
+    }
+
+    fn check_state(&self, scope: &mut StateScope, next: &dyn Handler) {
+        scope.require::<S, F>();
+        next.check_state(scope);
     }
 }
 
@@ -295,10 +449,12 @@ pub struct ArcMiddlewareFn<S, F> {
     _marker: PhantomData<fn(&S)>,
 }
 
-impl<S, F> Middleware for ArcMiddlewareFn<S, F>
+impl<S, F, R> Middleware for ArcMiddlewareFn<S, F>
 where
     S: Send + Sync + 'static,
-    F: for<'a> AsyncFn<'a, (Arc<S>, Request, &'a dyn Handler), Output = Result<Response>>,
+    F: for<'a> AsyncFn<'a, (Arc<S>, Request, &'a dyn Handler), Output = R>,
+    R: TryInto<Response>,
+    R::Error: Into<Error>,
 {
     fn handle<'t, 'n, 'a>(
         &'t self,
@@ -310,15 +466,18 @@ where
         'n: 'a,
         Self: 'a,
     {
-        let state = match state::inject::<S>() {
-            Some(s) => s,
-            None => panic!(
-                "failed to inject state <{}> for middleware <{}>",
-                type_name::<S>(),
-                type_name::<F>(),
-            ),
-        };
-        Box::pin(async move { AsyncFn::call(&self.f, (state, req, next)).await })
+        Box::pin(async move {
+            let state = scope::resolve_state::<S, F>(&req).await?;
+            AsyncFn::call(&self.f, (state, req, next))
+                .await
+                .try_into()
+                .map_err(Into::into)
+        })
+    }
+
+    fn check_state(&self, scope: &mut StateScope, next: &dyn Handler) {
+        scope.require::<S, F>();
+        next.check_state(scope);
     }
 }
 
@@ -333,3 +492,172 @@ where
         }
     }
 }
+
+/// Creates a middleware which runs `f` before the next handler.
+///
+/// `f` may modify the request or return an error to skip the next handler.
+pub fn before<F>(f: F) -> BeforeFn<F> {
+    BeforeFn { f }
+}
+
+pub struct BeforeFn<F> {
+    f: F,
+}
+
+impl<F> Middleware for BeforeFn<F>
+where
+    F: for<'a> AsyncFn<'a, (Request,), Output = Result<Request>>,
+{
+    fn handle<'t, 'n, 'a>(
+        &'t self,
+        req: Request,
+        next: &'a dyn Handler,
+    ) -> BoxFuture<'a, Result<Response>>
+    where
+        't: 'a,
+        'n: 'a,
+        Self: 'a,
+    {
+        Box::pin(async move {
+            let req = AsyncFn::call(&self.f, (req,)).await?;
+            next.handle(req).await
+        })
+    }
+}
+
+impl<F> Clone for BeforeFn<F>
+where
+    F: Clone,
+{
+    fn clone(&self) -> Self {
+        Self { f: self.f.clone() }
+    }
+}
+
+/// Creates a middleware which maps the response of the next handler.
+///
+/// Errors of the next handler are returned as is.
+pub fn map_response<F>(f: F) -> MapResponseFn<F> {
+    MapResponseFn { f }
+}
+
+pub struct MapResponseFn<F> {
+    f: F,
+}
+
+impl<F, R> Middleware for MapResponseFn<F>
+where
+    F: for<'a> AsyncFn<'a, (Response,), Output = R>,
+    R: TryInto<Response>,
+    R::Error: Into<Error>,
+{
+    fn handle<'t, 'n, 'a>(
+        &'t self,
+        req: Request,
+        next: &'a dyn Handler,
+    ) -> BoxFuture<'a, Result<Response>>
+    where
+        't: 'a,
+        'n: 'a,
+        Self: 'a,
+    {
+        Box::pin(async move {
+            let res = next.handle(req).await?;
+            AsyncFn::call(&self.f, (res,))
+                .await
+                .try_into()
+                .map_err(Into::into)
+        })
+    }
+}
+
+impl<F> Clone for MapResponseFn<F>
+where
+    F: Clone,
+{
+    fn clone(&self) -> Self {
+        Self { f: self.f.clone() }
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn blocking() {
+    fn count(_: Request) -> String {
+        let n = state::inject::<u32>().unwrap();
+        format!("{}", n)
+    }
+
+    fn panic(_: Request) -> String {
+        panic!("boom")
+    }
+
+    let req = || Request::from_hyper(HyperRequest::default());
+
+    let h = blocking_handler(count).with_state(Arc::new(42_u32));
+    let res = h.handle(req()).await.unwrap();
+    let body = hyper::body::to_bytes(res.into_hyper().into_body()).await;
+    assert_eq!(body.unwrap(), "42");
+
+    let err = blocking_handler(panic).handle(req()).await.unwrap_err();
+    assert_eq!(err.downcast_ref::<PanicError>().unwrap().message(), "boom");
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn middleware_fns() {
+    use crate::error::StatusError;
+    use crate::http::StatusCode;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    async fn endpoint(_: Request) -> &'static str {
+        CALLS.fetch_add(1, Ordering::SeqCst);
+        "endpoint"
+    }
+
+    async fn deny(req: Request) -> Result<Request> {
+        match req.uri().path() {
+            "/denied" => Err(StatusError::new(StatusCode::FORBIDDEN).into()),
+            _ => Ok(req),
+        }
+    }
+
+    async fn created(res: Response) -> Response {
+        res.with_status(StatusCode::CREATED)
+    }
+
+    async fn teapot(_: Request, _: &dyn Handler) -> StatusCode {
+        StatusCode::IM_A_TEAPOT
+    }
+
+    let req = |uri: &str| {
+        Request::from_hyper(
+            crate::http::Request::builder()
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap(),
+        )
+    };
+
+    let h = handler(endpoint).wrap(before(deny));
+    assert_eq!(h.handle(req("/")).await.unwrap().status(), StatusCode::OK);
+    assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+    let err = h.handle(req("/denied")).await.unwrap_err();
+    let err = err.downcast_ref::<StatusError>().unwrap();
+    assert_eq!(err.status, StatusCode::FORBIDDEN);
+    assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+
+    let h = handler(endpoint).wrap(map_response(created));
+    let res = h.handle(req("/")).await.unwrap();
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let body = hyper::body::to_bytes(res.into_hyper().into_body()).await;
+    assert_eq!(body.unwrap(), "endpoint");
+
+    let h = handler(endpoint).wrap(middleware(teapot));
+    let res = h.handle(req("/")).await.unwrap();
+    assert_eq!(res.status(), StatusCode::IM_A_TEAPOT);
+    assert_eq!(CALLS.load(Ordering::SeqCst), 2);
+}
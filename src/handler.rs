@@ -1,7 +1,7 @@
 use crate::internal_prelude::*;
 use crate::middleware::Middleware;
 use crate::server::Server;
-use crate::state;
+use crate::state::{self, RequestState, StateScope};
 
 use std::sync::Arc;
 
@@ -13,6 +13,13 @@ pub trait Handler: Send + Sync {
         't: 'a,
         Self: 'a;
 
+    /// Reports the states required by this handler and its children.
+    ///
+    /// See [`state::verify`].
+    fn check_state(&self, scope: &mut StateScope) {
+        let _ = scope;
+    }
+
     fn with_state<S>(self, state: Arc<S>) -> WithState<Self, S>
     where
         Self: Sized,
@@ -21,6 +28,19 @@ pub trait Handler: Send + Sync {
         WithState { h: self, s: state }
     }
 
+    /// Attaches `state` to the extensions of every request.
+    ///
+    /// Unlike [`Handler::with_state`], the state is accessed by [`Request::state`]
+    /// and does not depend on the poll context.
+    /// As with nested [`WithState`]s, the innermost state of a type wins.
+    fn with_request_state<S>(self, state: Arc<S>) -> WithRequestState<Self, S>
+    where
+        Self: Sized,
+        S: Send + Sync + 'static,
+    {
+        WithRequestState { h: self, s: state }
+    }
+
     fn wrap<M>(self, middleware: M) -> Wrap<Self, M>
     where
         Self: Sized,
@@ -55,6 +75,10 @@ impl Handler for Box<dyn Handler> {
     {
         Handler::handle(&**self, req)
     }
+
+    fn check_state(&self, scope: &mut StateScope) {
+        Handler::check_state(&**self, scope)
+    }
 }
 
 pub struct WithState<H, S> {
@@ -67,16 +91,47 @@ where
     H: Handler,
     S: Send + Sync + 'static,
 {
-    fn handle<'t, 'a>(&'t self, req: Request) -> BoxFuture<'a, Result<Response>>
+    fn handle<'t, 'a>(&'t self, mut req: Request) -> BoxFuture<'a, Result<Response>>
     where
         't: 'a,
         Self: 'a,
     {
+        // The innermost state wins, so an outer request state of `S` is shadowed.
+        req.extensions_mut().remove::<RequestState<S>>();
         let mut fut = state::enter(&self.s, || self.h.handle(req));
         Box::pin(future::poll_fn(move |cx| {
             state::enter(&self.s, || fut.as_mut().poll(cx))
         }))
     }
+
+    fn check_state(&self, scope: &mut StateScope) {
+        scope.provide::<S>(|scope| self.h.check_state(scope))
+    }
+}
+
+pub struct WithRequestState<H, S> {
+    h: H,
+    s: Arc<S>,
+}
+
+impl<H, S> Handler for WithRequestState<H, S>
+where
+    H: Handler,
+    S: Send + Sync + 'static,
+{
+    fn handle<'t, 'a>(&'t self, mut req: Request) -> BoxFuture<'a, Result<Response>>
+    where
+        't: 'a,
+        Self: 'a,
+    {
+        req.extensions_mut()
+            .insert(RequestState(Arc::clone(&self.s)));
+        self.h.handle(req)
+    }
+
+    fn check_state(&self, scope: &mut StateScope) {
+        scope.provide::<S>(|scope| self.h.check_state(scope))
+    }
 }
 
 pub struct Wrap<H, M> {
@@ -96,4 +151,50 @@ where
     {
         self.m.handle(req, &self.h)
     }
+
+    fn check_state(&self, scope: &mut StateScope) {
+        self.m.check_state(scope, &self.h)
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn request_state() {
+    use crate::functional::handler;
+
+    async fn read(req: Request) -> String {
+        assert!(req.extensions().get::<RequestState<u32>>().is_some());
+        let state = req.state::<u32>().unwrap();
+        let n = tokio::spawn(async move { *state }).await.unwrap();
+        n.to_string()
+    }
+
+    async fn read_any(req: Request) -> String {
+        req.state::<u32>().unwrap().to_string()
+    }
+
+    async fn call(h: impl Handler) -> String {
+        let req = Request::from_hyper(HyperRequest::default());
+        let res = h.handle(req).await.unwrap();
+        let body = hyper::body::to_bytes(res.into_hyper().into_body()).await;
+        String::from_utf8(body.unwrap().to_vec()).unwrap()
+    }
+
+    let h = handler(read).with_request_state(Arc::new(1_u32));
+    assert_eq!(call(h).await, "1");
+
+    let h = handler(read_any)
+        .with_state(Arc::new(2_u32))
+        .with_request_state(Arc::new(1_u32));
+    assert_eq!(call(h).await, "2");
+
+    let h = handler(read_any)
+        .with_request_state(Arc::new(2_u32))
+        .with_state(Arc::new(1_u32));
+    assert_eq!(call(h).await, "2");
+
+    let h = handler(read_any)
+        .with_request_state(Arc::new(2_u32))
+        .with_request_state(Arc::new(1_u32));
+    assert_eq!(call(h).await, "2");
 }
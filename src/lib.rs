@@ -3,15 +3,22 @@
 
 pub mod body;
 pub mod error;
+pub mod extract;
 pub mod functional;
 pub mod handler;
 pub mod http;
 pub mod middleware;
+pub mod proxy;
 pub mod request;
 pub mod response;
 pub mod router;
+pub mod scope;
 pub mod server;
 pub mod state;
+pub mod validate;
+
+#[cfg(feature = "tower")]
+pub mod tower;
 
 pub(crate) mod internal_prelude {
     pub use crate::error::{Error, Result};
@@ -21,8 +28,6 @@ pub(crate) mod internal_prelude {
     pub use crate::response::Response;
 
     pub use std::future::Future;
-    pub use std::pin::Pin;
-    pub use std::task::{Context, Poll};
 
     pub use futures::future::BoxFuture;
 
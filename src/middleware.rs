@@ -1,5 +1,12 @@
+use crate::error::PanicError;
 use crate::handler::Handler;
 use crate::internal_prelude::*;
+use crate::state::StateScope;
+
+use std::iter::FromIterator;
+use std::panic::{self, AssertUnwindSafe};
+
+use futures::FutureExt;
 
 pub trait Middleware: Send + Sync {
     fn handle<'t, 'n, 'a>(
@@ -12,6 +19,11 @@ pub trait Middleware: Send + Sync {
         'n: 'a,
         Self: 'a;
 
+    /// Reports the states required by this middleware and `next`.
+    fn check_state(&self, scope: &mut StateScope, next: &dyn Handler) {
+        next.check_state(scope)
+    }
+
     fn boxed(self) -> Box<dyn Middleware>
     where
         Self: Sized + 'static,
@@ -33,4 +45,173 @@ impl Middleware for Box<dyn Middleware> {
     {
         Middleware::handle(&**self, req, next)
     }
+
+    fn check_state(&self, scope: &mut StateScope, next: &dyn Handler) {
+        Middleware::check_state(&**self, scope, next)
+    }
+}
+
+/// An ordered list of middlewares, from the outermost to the innermost
+///
+/// Unlike nested [`Handler::wrap`] calls, a stack can be built at runtime.
+/// [`MiddlewareStack::apply`] links the chain once. Each layer costs two dynamic calls
+/// per request, one into the layer and one into its middleware, which passes the next
+/// layer on without another indirection.
+///
+/// The dispatch is not allocation-free: [`Middleware::handle`] returns a boxed future,
+/// so every layer allocates one per request.
+#[derive(Default)]
+pub struct MiddlewareStack {
+    middlewares: Vec<Box<dyn Middleware>>,
+}
+
+impl MiddlewareStack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a middleware inside the existing ones.
+    pub fn push(&mut self, middleware: impl Middleware + 'static) -> &mut Self {
+        self.middlewares.push(Box::new(middleware));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.middlewares.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.middlewares.is_empty()
+    }
+
+    /// Wraps `h` with the stack. The first middleware sees the request first.
+    pub fn apply(self, h: impl Handler + 'static) -> Box<dyn Handler> {
+        let mut next = h.boxed();
+        for middleware in self.middlewares.into_iter().rev() {
+            next = Box::new(Layer { middleware, next });
+        }
+        next
+    }
+}
+
+/// A middleware of an applied [`MiddlewareStack`] and the rest of the chain
+struct Layer {
+    middleware: Box<dyn Middleware>,
+    next: Box<dyn Handler>,
+}
+
+impl Handler for Layer {
+    fn handle<'t, 'a>(&'t self, req: Request) -> BoxFuture<'a, Result<Response>>
+    where
+        't: 'a,
+        Self: 'a,
+    {
+        Middleware::handle(&*self.middleware, req, &*self.next)
+    }
+
+    fn check_state(&self, scope: &mut StateScope) {
+        Middleware::check_state(&*self.middleware, scope, &*self.next)
+    }
+}
+
+impl From<Vec<Box<dyn Middleware>>> for MiddlewareStack {
+    fn from(middlewares: Vec<Box<dyn Middleware>>) -> Self {
+        Self { middlewares }
+    }
+}
+
+impl FromIterator<Box<dyn Middleware>> for MiddlewareStack {
+    fn from_iter<I: IntoIterator<Item = Box<dyn Middleware>>>(iter: I) -> Self {
+        Self {
+            middlewares: iter.into_iter().collect(),
+        }
+    }
+}
+
+/// A middleware converting panics of the next handler into [`PanicError`]s
+///
+/// [`Server`](crate::server::Server) enables it by default and passes the error
+/// to its error responder, which logs the panic and renders 500 by default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CatchPanic;
+
+impl Middleware for CatchPanic {
+    fn handle<'t, 'n, 'a>(
+        &'t self,
+        req: Request,
+        next: &'n dyn Handler,
+    ) -> BoxFuture<'a, Result<Response>>
+    where
+        't: 'a,
+        'n: 'a,
+        Self: 'a,
+    {
+        let fut = match panic::catch_unwind(AssertUnwindSafe(|| next.handle(req))) {
+            Ok(fut) => fut,
+            Err(payload) => return Box::pin(async move { Err(panic_error(&*payload)) }),
+        };
+        Box::pin(async move {
+            match AssertUnwindSafe(fut).catch_unwind().await {
+                Ok(ret) => ret,
+                Err(payload) => Err(panic_error(&*payload)),
+            }
+        })
+    }
+}
+
+fn panic_error(payload: &(dyn std::any::Any + Send)) -> Error {
+    PanicError::from_payload(payload).into()
+}
+
+#[test]
+fn stack_order() {
+    use crate::functional::{handler, middleware};
+
+    use std::sync::Mutex;
+
+    use futures::executor::block_on;
+
+    static TRACE: Mutex<Vec<&'static str>> = Mutex::new(Vec::new());
+
+    async fn first(req: Request, next: &dyn Handler) -> Result<Response> {
+        TRACE.lock().unwrap().push("first");
+        next.handle(req).await
+    }
+
+    async fn second(req: Request, next: &dyn Handler) -> Result<Response> {
+        TRACE.lock().unwrap().push("second");
+        next.handle(req).await
+    }
+
+    async fn endpoint(_: Request) {
+        TRACE.lock().unwrap().push("endpoint");
+    }
+
+    let stack: MiddlewareStack = vec![middleware(first).boxed(), middleware(second).boxed()].into();
+    let h = stack.apply(handler(endpoint));
+
+    let req = Request::from_hyper(HyperRequest::default());
+    block_on(h.handle(req)).unwrap();
+    assert_eq!(*TRACE.lock().unwrap(), ["first", "second", "endpoint"]);
+}
+
+#[test]
+fn catch_panic() {
+    use crate::functional::handler;
+    use crate::http::StatusCode;
+
+    use futures::executor::block_on;
+
+    async fn boom(_: Request) {
+        panic!("boom")
+    }
+
+    let h = handler(boom).wrap(CatchPanic);
+    let req = Request::from_hyper(HyperRequest::default());
+    let err = block_on(h.handle(req)).unwrap_err();
+    assert_eq!(err.downcast_ref::<PanicError>().unwrap().message(), "boom");
+    assert_eq!(
+        crate::error::error_response(err).status(),
+        StatusCode::INTERNAL_SERVER_ERROR
+    );
 }
@@ -0,0 +1,274 @@
+use crate::http::{header, HeaderMap};
+use crate::internal_prelude::*;
+
+use std::net::{IpAddr, SocketAddr};
+
+pub use ipnet::IpNet;
+
+/// The original client of a request, resolved by [`TrustedProxies`]
+#[derive(Debug, Clone)]
+pub(crate) struct ForwardedInfo {
+    /// `None` if the peer is a trusted Unix socket and no hop is forwarded
+    pub(crate) client_ip: Option<IpAddr>,
+    pub(crate) scheme: Option<String>,
+    pub(crate) host: Option<String>,
+}
+
+/// A middleware resolving the original client from `Forwarded` or `X-Forwarded-*` headers
+///
+/// The headers are only believed if the peer is a trusted proxy.
+/// The forwarding chain is walked from the nearest hop, and the first untrusted address is the client.
+/// See [`Request::client_ip`], [`Request::scheme`] and [`Request::host`].
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    trusted: Vec<IpNet>,
+    trust_unix: bool,
+}
+
+/// A hop in the forwarding chain
+#[derive(Debug, Default)]
+struct Hop {
+    ip: Option<IpAddr>,
+    proto: Option<String>,
+    host: Option<String>,
+}
+
+impl TrustedProxies {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trusts the proxies in `net`, such as `"10.0.0.0/8".parse()?`.
+    pub fn trust(&mut self, net: IpNet) -> &mut Self {
+        self.trusted.push(net);
+        self
+    }
+
+    /// Trusts peers connected over Unix sockets, such as a proxy on the same host.
+    pub fn trust_unix(&mut self, enabled: bool) -> &mut Self {
+        self.trust_unix = enabled;
+        self
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted.iter().any(|net| net.contains(&ip))
+    }
+
+    /// Resolves the client behind `peer`, which is `None` for a trusted Unix socket.
+    fn resolve(&self, peer: Option<IpAddr>, headers: &HeaderMap) -> ForwardedInfo {
+        let mut info = ForwardedInfo {
+            client_ip: peer,
+            scheme: None,
+            host: None,
+        };
+        if let Some(peer) = peer {
+            if !self.is_trusted(peer) {
+                return info;
+            }
+        }
+
+        let hops = match parse_forwarded(headers) {
+            Some(hops) => hops,
+            None => parse_x_forwarded(headers),
+        };
+
+        // the last hop is added by the nearest proxy
+        for hop in hops.iter().rev() {
+            let ip = match hop.ip {
+                Some(ip) => ip,
+                None => break,
+            };
+            info.client_ip = Some(ip);
+            info.scheme = hop.proto.clone().or(info.scheme);
+            info.host = hop.host.clone().or(info.host);
+            if !self.is_trusted(ip) {
+                break;
+            }
+        }
+        info
+    }
+}
+
+impl Middleware for TrustedProxies {
+    fn handle<'t, 'n, 'a>(
+        &'t self,
+        mut req: Request,
+        next: &'n dyn Handler,
+    ) -> BoxFuture<'a, Result<Response>>
+    where
+        't: 'a,
+        'n: 'a,
+        Self: 'a,
+    {
+        // a connection without a socket address is a Unix socket
+        let peer = match req.connection_info() {
+            Some(conn) => match conn.peer_addr() {
+                Some(addr) => Some(addr.ip()),
+                None if self.trust_unix => None,
+                None => return next.handle(req),
+            },
+            None => return next.handle(req),
+        };
+        let info = self.resolve(peer, req.headers());
+        req.extensions_mut().insert(info);
+        next.handle(req)
+    }
+}
+
+fn header_values<'h>(headers: &'h HeaderMap, name: &str) -> Vec<&'h str> {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|s| s.split(','))
+        .map(str::trim)
+        .collect()
+}
+
+/// Parses `Forwarded` (RFC 7239), or returns `None` if absent
+fn parse_forwarded(headers: &HeaderMap) -> Option<Vec<Hop>> {
+    let elements = header_values(headers, header::FORWARDED.as_str());
+    if elements.is_empty() {
+        return None;
+    }
+
+    let hops = elements
+        .into_iter()
+        .map(|element| {
+            let mut hop = Hop::default();
+            for pair in element.split(';') {
+                let (name, value) = match pair.split_once('=') {
+                    Some((n, v)) => (n.trim(), v.trim().trim_matches('"')),
+                    None => continue,
+                };
+                if name.eq_ignore_ascii_case("for") {
+                    hop.ip = parse_node(value);
+                } else if name.eq_ignore_ascii_case("proto") {
+                    hop.proto = Some(value.to_ascii_lowercase());
+                } else if name.eq_ignore_ascii_case("host") {
+                    hop.host = Some(value.to_owned());
+                }
+            }
+            hop
+        })
+        .collect();
+    Some(hops)
+}
+
+/// Parses `X-Forwarded-For`, `X-Forwarded-Proto` and `X-Forwarded-Host`
+///
+/// The proto and host are attributed to the last hop.
+fn parse_x_forwarded(headers: &HeaderMap) -> Vec<Hop> {
+    let mut hops: Vec<Hop> = header_values(headers, "x-forwarded-for")
+        .into_iter()
+        .map(|value| Hop {
+            ip: parse_node(value),
+            ..Hop::default()
+        })
+        .collect();
+
+    if let Some(last) = hops.last_mut() {
+        let proto = header_values(headers, "x-forwarded-proto").pop();
+        let host = header_values(headers, "x-forwarded-host").pop();
+        last.proto = proto.map(str::to_ascii_lowercase);
+        last.host = host.map(str::to_owned);
+    }
+    hops
+}
+
+/// Parses a node like `192.0.2.60`, `192.0.2.60:4711` or `[2001:db8::17]:4711`
+fn parse_node(s: &str) -> Option<IpAddr> {
+    if let Ok(ip) = s.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    if let Ok(addr) = s.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+    s.strip_prefix('[')
+        .and_then(|s| s.strip_suffix(']'))
+        .and_then(|s| s.parse().ok())
+}
+
+#[test]
+fn forwarded_chain() {
+    use crate::http::HeaderValue;
+
+    let mut proxies = TrustedProxies::new();
+    proxies.trust("10.0.0.0/8".parse().unwrap());
+
+    let peer: IpAddr = "10.0.0.1".parse().unwrap();
+    let mut headers = HeaderMap::new();
+
+    let value =
+        "for=198.51.100.7, for=\"[2001:db8::17]:4711\";proto=HTTPS;host=example.com, for=10.0.0.2";
+    headers.insert(header::FORWARDED, HeaderValue::from_static(value));
+    let info = proxies.resolve(Some(peer), &headers);
+    assert_eq!(info.client_ip, "2001:db8::17".parse().ok());
+    assert_eq!(info.scheme.as_deref(), Some("https"));
+    assert_eq!(info.host.as_deref(), Some("example.com"));
+
+    headers.clear();
+    let value = HeaderValue::from_static("203.0.113.9, 10.1.2.3");
+    headers.insert("x-forwarded-for", value);
+    headers.insert("x-forwarded-proto", HeaderValue::from_static("https"));
+    let info = proxies.resolve(Some(peer), &headers);
+    assert_eq!(info.client_ip, "203.0.113.9".parse().ok());
+    assert_eq!(info.scheme.as_deref(), Some("https"));
+
+    let untrusted: IpAddr = "192.0.2.1".parse().unwrap();
+    let info = proxies.resolve(Some(untrusted), &headers);
+    assert_eq!(info.client_ip, Some(untrusted));
+    assert!(info.scheme.is_none());
+
+    let info = proxies.resolve(None, &headers);
+    assert_eq!(info.client_ip, "203.0.113.9".parse().ok());
+    assert_eq!(info.scheme.as_deref(), Some("https"));
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn forwarded_routing() {
+    use crate::functional::handler;
+    use crate::http::{self, HeaderValue};
+    use crate::router::HostRouter;
+    use crate::server::ConnectionInfo;
+
+    async fn link(req: Request) -> String {
+        let client = req.client_ip().map(|ip| ip.to_string()).unwrap_or_default();
+        let uri = req.absolute_uri("/posts?page=2").unwrap();
+        format!("{} {}", client, uri)
+    }
+
+    let mut hosts = HostRouter::new();
+    hosts.add_host("api.example.com", handler(link).boxed());
+
+    let mut proxies = TrustedProxies::new();
+    proxies
+        .trust("10.0.0.0/8".parse().unwrap())
+        .trust_unix(true);
+    let h = hosts.wrap(proxies);
+
+    let call = |peer: Option<SocketAddr>| {
+        let mut req = http::Request::new(Body::empty());
+        let headers = req.headers_mut();
+        headers.insert(header::HOST, HeaderValue::from_static("internal:8080"));
+        headers.insert("x-forwarded-for", HeaderValue::from_static("203.0.113.9"));
+        headers.insert("x-forwarded-proto", HeaderValue::from_static("https"));
+        let host = HeaderValue::from_static("API.example.com:443");
+        headers.insert("x-forwarded-host", host);
+        req.extensions_mut().insert(ConnectionInfo::new(peer, None));
+        h.handle(Request::from_hyper(req))
+    };
+    let body = |res: Response| hyper::body::to_bytes(res.into_hyper().into_body());
+
+    let expected = "203.0.113.9 https://API.example.com:443/posts?page=2";
+
+    let res = call(Some(([10, 0, 0, 1], 4000).into())).await.unwrap();
+    assert_eq!(body(res).await.unwrap(), expected);
+
+    let res = call(None).await.unwrap();
+    assert_eq!(body(res).await.unwrap(), expected);
+
+    let res = call(Some(([192, 0, 2, 1], 4000).into())).await.unwrap();
+    assert_eq!(res.status(), crate::http::StatusCode::NOT_FOUND);
+}
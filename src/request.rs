@@ -1,11 +1,21 @@
-use crate::body::BodyError;
+use crate::body::{self, BodyError, BodyStream};
+use crate::http::{header, HeaderValue, Uri};
 use crate::internal_prelude::*;
+use crate::proxy::ForwardedInfo;
+use crate::scope;
+use crate::server::ConnectionInfo;
+use crate::state::{self, RequestState, StateError};
 
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
 use std::ops;
+use std::sync::Arc;
 
 use bytes::{BufMut, Bytes, BytesMut};
 use futures::stream::StreamExt;
 
+struct BufferedBody(Bytes);
+
 #[derive(Debug)]
 pub struct Request {
     inner: Box<HyperRequest>,
@@ -18,7 +28,149 @@ impl Request {
         }
     }
 
+    #[cfg(feature = "tower")]
+    pub(crate) fn into_hyper(self) -> HyperRequest {
+        *self.inner
+    }
+
+    /// Reads the whole body and decodes it according to `Content-Encoding`.
+    ///
+    /// `length_limit` applies to both the received and the decoded size.
+    /// Returns the buffered body if [`Request::buffer_body`] has been called.
     pub async fn body_bytes(&mut self, length_limit: usize) -> Result<Bytes> {
+        if let Some(bytes) = self.buffered_body() {
+            if bytes.len() > length_limit {
+                return Err(BodyError::LengthLimitExceeded.into());
+            }
+            return Ok(bytes.clone());
+        }
+
+        let codings = body::parse_content_encoding(self.headers())?;
+        let bytes = self.raw_body_bytes(length_limit).await?;
+        Ok(body::decode_content_async(bytes, codings, length_limit).await?)
+    }
+
+    /// Reads and decodes the whole body once, so that it can be read again later.
+    ///
+    /// The body of the request is replaced by the decoded bytes
+    /// and `Content-Encoding` and `Transfer-Encoding` are replaced by `Content-Length`,
+    /// which keeps the body available for handlers that stream it.
+    pub async fn buffer_body(&mut self, length_limit: usize) -> Result<Bytes> {
+        if self.buffered_body().is_some() {
+            return self.body_bytes(length_limit).await;
+        }
+
+        let bytes = self.body_bytes(length_limit).await?;
+
+        let headers = self.headers_mut();
+        headers.remove(header::CONTENT_ENCODING);
+        headers.remove(header::TRANSFER_ENCODING);
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from(bytes.len()));
+        *self.body_mut() = Body::from(bytes.clone());
+        self.extensions_mut().insert(BufferedBody(bytes.clone()));
+
+        Ok(bytes)
+    }
+
+    /// Returns the body buffered by [`Request::buffer_body`].
+    pub fn buffered_body(&self) -> Option<&Bytes> {
+        self.extensions().get::<BufferedBody>().map(|b| &b.0)
+    }
+
+    /// Returns the state attached to the request,
+    /// or the state injected by an enclosing [`WithState`](crate::handler::WithState).
+    ///
+    /// The returned `Arc` can be moved into spawned tasks.
+    pub fn state<S>(&self) -> Option<Arc<S>>
+    where
+        S: Send + Sync + 'static,
+    {
+        match self.extensions().get::<RequestState<S>>() {
+            Some(s) => Some(Arc::clone(&s.0)),
+            None => state::inject::<S>(),
+        }
+    }
+
+    /// Returns the connection the request arrives on.
+    pub fn connection_info(&self) -> Option<&ConnectionInfo> {
+        self.extensions().get::<ConnectionInfo>()
+    }
+
+    /// Returns the address of the peer, which may be a proxy.
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.connection_info().and_then(|c| c.peer_addr())
+    }
+
+    /// Returns the IP of the client, resolved by [`TrustedProxies`](crate::proxy::TrustedProxies)
+    /// if it is in front of the handler.
+    pub fn client_ip(&self) -> Option<IpAddr> {
+        match self.extensions().get::<ForwardedInfo>() {
+            Some(info) => info.client_ip,
+            None => self.remote_addr().map(|addr| addr.ip()),
+        }
+    }
+
+    /// Returns the scheme requested by the client, `http` or `https` usually.
+    ///
+    /// The scheme comes from trusted proxies or the connection.
+    /// The URI is only consulted for requests without a connection,
+    /// since any client can send an absolute `https` URI over plain TCP.
+    pub fn scheme(&self) -> &str {
+        let forwarded = self.extensions().get::<ForwardedInfo>();
+        if let Some(scheme) = forwarded.and_then(|info| info.scheme.as_deref()) {
+            return scheme;
+        }
+        match self.connection_info() {
+            Some(conn) if conn.tls().is_some() => "https",
+            Some(_) => "http",
+            None => self.uri().scheme_str().unwrap_or("http"),
+        }
+    }
+
+    /// Returns the host requested by the client, which may include a port.
+    pub fn host(&self) -> Option<&str> {
+        let forwarded = self.extensions().get::<ForwardedInfo>();
+        if let Some(host) = forwarded.and_then(|info| info.host.as_deref()) {
+            return Some(host);
+        }
+        match self.headers().get(header::HOST) {
+            Some(v) => v.to_str().ok(),
+            None => self.uri().authority().map(|a| a.as_str()),
+        }
+    }
+
+    /// Builds an absolute URI for `path_and_query` from [`Request::scheme`] and [`Request::host`],
+    /// for redirects and links that must point back at the client-facing address.
+    ///
+    /// Returns `None` if the host is unknown or the result is not a valid URI.
+    pub fn absolute_uri(&self, path_and_query: &str) -> Option<Uri> {
+        Uri::builder()
+            .scheme(self.scheme())
+            .authority(self.host()?)
+            .path_and_query(path_and_query)
+            .build()
+            .ok()
+    }
+
+    /// Returns the request-scoped value of `T`, constructing it on first access.
+    ///
+    /// See [`RequestScope`](crate::scope::RequestScope).
+    pub async fn scoped<T>(&self) -> Result<Arc<T>>
+    where
+        T: Send + Sync + 'static,
+    {
+        match scope::resolve::<T>(self).await? {
+            Some(value) => Ok(value),
+            None => Err(StateError::new::<T>().into()),
+        }
+    }
+
+    /// Takes the body out of the request as a [`BodyStream`].
+    pub fn body_stream(&mut self) -> BodyStream {
+        BodyStream::new(mem::take(self.body_mut()))
+    }
+
+    async fn raw_body_bytes(&mut self, length_limit: usize) -> Result<Bytes> {
         let body = self.body_mut();
 
         let mut bufs: Vec<Bytes> = Vec::new();
@@ -55,3 +207,40 @@ impl ops::DerefMut for Request {
         self.inner.as_mut()
     }
 }
+
+#[test]
+fn replay_body() {
+    use futures::executor::block_on;
+
+    let mut req = Request::from_hyper(HyperRequest::new(Body::from("hello")));
+    req.headers_mut().insert(
+        header::TRANSFER_ENCODING,
+        HeaderValue::from_static("chunked"),
+    );
+
+    let bytes = block_on(req.buffer_body(5)).unwrap();
+    assert_eq!(bytes, "hello");
+    assert!(!req.headers().contains_key(header::TRANSFER_ENCODING));
+    assert_eq!(req.headers()[header::CONTENT_LENGTH], "5");
+    assert_eq!(block_on(req.body_bytes(5)).unwrap(), "hello");
+    assert!(block_on(req.body_bytes(4)).is_err());
+
+    let body = mem::take(req.body_mut());
+    let bytes = block_on(hyper::body::to_bytes(body)).unwrap();
+    assert_eq!(bytes, "hello");
+}
+
+#[test]
+fn scheme_from_connection() {
+    let mut req = Request::from_hyper(HyperRequest::default());
+    *req.uri_mut() = Uri::from_static("https://example.com/login");
+    assert_eq!(req.scheme(), "https");
+
+    let conn = ConnectionInfo::new(Some(SocketAddr::from(([127, 0, 0, 1], 8080))), None);
+    req.extensions_mut().insert(conn);
+    assert_eq!(req.scheme(), "http");
+    assert_eq!(
+        req.absolute_uri("/next").unwrap(),
+        "http://example.com/next"
+    );
+}
@@ -6,6 +6,7 @@ use std::ops;
 
 // use futures::future::{self, Either, Ready};
 // use pin_project::pin_project;
+use futures::stream::{Stream, StreamExt};
 use serde::Serialize;
 
 #[derive(Debug)]
@@ -57,6 +58,27 @@ impl Response {
         Ok(res)
     }
 
+    /// Creates a streaming NDJSON response.
+    ///
+    /// The body is aborted if an item fails to serialize.
+    pub fn ndjson<S>(stream: S) -> Self
+    where
+        S: Stream + Send + 'static,
+        S::Item: Serialize,
+    {
+        let lines = stream.map(|item| {
+            let mut bytes_vec = serde_json::to_vec(&item)?;
+            bytes_vec.push(b'\n');
+            Ok::<_, serde_json::Error>(bytes_vec)
+        });
+        let mut res = Response::new_ok(Body::wrap_stream(lines));
+        res.inner.headers_mut().insert(
+            http::header::CONTENT_TYPE,
+            HeaderValue::from_static("application/x-ndjson"),
+        );
+        res
+    }
+
     pub fn with_status(mut self, status: StatusCode) -> Self {
         *self.status_mut() = status;
         self
@@ -121,3 +143,15 @@ where
         Response::json(value)
     }
 }
+
+pub struct Ndjson<S>(pub S);
+
+impl<S> From<Ndjson<S>> for Response
+where
+    S: Stream + Send + 'static,
+    S::Item: Serialize,
+{
+    fn from(Ndjson(stream): Ndjson<S>) -> Self {
+        Response::ndjson(stream)
+    }
+}
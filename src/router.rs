@@ -1,6 +1,7 @@
 use crate::error::StatusError;
 use crate::http::Method;
 use crate::internal_prelude::*;
+use crate::state::StateScope;
 
 use std::ops::{Range, RangeFrom};
 use std::str::FromStr;
@@ -23,6 +24,14 @@ impl CaptureOwned {
     fn get_param(&self, name: &str) -> Option<&str> {
         self.captures.get_param(self.path.as_ref(), name)
     }
+
+    pub fn params(&self) -> impl Iterator<Item = (&str, &str)> + '_ {
+        let path: &str = self.path.as_ref();
+        let params = self.captures.params.as_deref().unwrap_or(&[]);
+        params
+            .iter()
+            .map(move |&(name, ref range)| (name, &path[range.clone()]))
+    }
 }
 
 pub trait SimpleRouterExt {
@@ -114,6 +123,12 @@ impl Handler for SimpleRouter {
             }
         })
     }
+
+    fn check_state(&self, scope: &mut StateScope) {
+        for h in self.effects.iter().chain(self.default.iter()) {
+            h.check_state(scope);
+        }
+    }
 }
 
 pub struct RouteSetter<'r> {
@@ -142,6 +157,76 @@ impl RouteSetter<'_> {
     define_method! {trace, Method::TRACE}
 }
 
+/// Dispatches requests by [`Request::host`], which honors [`TrustedProxies`](crate::proxy::TrustedProxies)
+///
+/// Hosts are matched case-insensitively, ignoring the port.
+#[derive(Default)]
+pub struct HostRouter {
+    hosts: Vec<(String, Box<dyn Handler>)>,
+    default: Option<Box<dyn Handler>>,
+}
+
+impl HostRouter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_host(&mut self, host: &str, h: Box<dyn Handler>) -> &mut Self {
+        self.hosts.push((host.to_ascii_lowercase(), h));
+        self
+    }
+
+    pub fn set_default(&mut self, h: Box<dyn Handler>) {
+        self.default = Some(h);
+    }
+
+    pub fn find(&self, host: &str) -> Option<&dyn Handler> {
+        let name = strip_port(host);
+        self.hosts
+            .iter()
+            .find(|(h, _)| h.eq_ignore_ascii_case(name))
+            .map(|(_, h)| &**h)
+    }
+}
+
+/// Strips the port from `example.com:8080` or `[::1]:8080`.
+fn strip_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        return match host.find(']') {
+            Some(end) => &host[..=end],
+            None => host,
+        };
+    }
+    match host.split_once(':') {
+        Some((name, _)) => name,
+        None => host,
+    }
+}
+
+impl Handler for HostRouter {
+    fn handle<'t, 'a>(&'t self, req: Request) -> BoxFuture<'a, Result<Response>>
+    where
+        't: 'a,
+        Self: 'a,
+    {
+        let h = match req.host().and_then(|host| self.find(host)) {
+            Some(h) => h,
+            None => match self.default.as_ref() {
+                Some(h) => &**h,
+                None => return Box::pin(async { Ok(StatusError::NOT_FOUND.into()) }),
+            },
+        };
+        h.handle(req)
+    }
+
+    fn check_state(&self, scope: &mut StateScope) {
+        let hosts = self.hosts.iter().map(|(_, h)| h);
+        for h in hosts.chain(self.default.iter()) {
+            h.check_state(scope);
+        }
+    }
+}
+
 #[derive(Default)]
 struct Router {
     routes: Vec<Route>,
@@ -0,0 +1,256 @@
+use crate::handler::Handler;
+use crate::internal_prelude::*;
+use crate::state::{StateError, StateScope};
+
+use std::any::{Any, TypeId};
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+
+/// A value living for a single request
+#[async_trait]
+pub trait RequestScoped: Send + Sync + 'static {
+    /// Runs after the handler returns, in the reverse order of construction.
+    ///
+    /// `success` is true if the handler returns a response whose status is not an error.
+    /// An error returned here fails the request if it has not failed yet.
+    async fn finish(self: Arc<Self>, success: bool) -> Result<()> {
+        let _ = success;
+        Ok(())
+    }
+}
+
+type Finisher = Box<dyn FnOnce(bool) -> BoxFuture<'static, Result<()>> + Send>;
+
+type Value = Arc<dyn Any + Send + Sync>;
+
+type Factory = Box<dyn Fn(&Request) -> BoxFuture<'static, Result<(Value, Finisher)>> + Send + Sync>;
+
+struct Provider {
+    id: TypeId,
+    factory: Arc<Factory>,
+}
+
+struct Slot {
+    id: TypeId,
+    factory: Arc<Factory>,
+    value: futures::lock::Mutex<Option<Value>>,
+}
+
+/// The values of a request, created lazily by the providers of [`RequestScope`]
+struct ScopeContainer {
+    slots: Vec<Slot>,
+    finishers: Mutex<Vec<Finisher>>,
+    parent: Option<Arc<ScopeContainer>>,
+}
+
+/// A middleware providing request-scoped values
+///
+/// Values are constructed on first access by [`Request::scoped`],
+/// the [`Scoped`](crate::extract::Scoped) extractor or `ref_handler`/`arc_handler`,
+/// and finished when the request ends.
+#[derive(Default)]
+pub struct RequestScope {
+    providers: Vec<Provider>,
+}
+
+impl RequestScope {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers an async constructor of `T`.
+    pub fn provide<T, F, U>(&mut self, f: F) -> &mut Self
+    where
+        T: RequestScoped,
+        F: Fn(&Request) -> U + Send + Sync + 'static,
+        U: Future<Output = Result<T>> + Send + 'static,
+    {
+        let factory: Factory = Box::new(move |req| {
+            let fut = f(req);
+            Box::pin(async move {
+                let value = Arc::new(fut.await?);
+                let finish: Finisher = {
+                    let value = Arc::clone(&value);
+                    Box::new(move |success| value.finish(success))
+                };
+                Ok((value as Value, finish))
+            })
+        });
+        self.providers.push(Provider {
+            id: TypeId::of::<T>(),
+            factory: Arc::new(factory),
+        });
+        self
+    }
+
+    fn container(&self, parent: Option<Arc<ScopeContainer>>) -> ScopeContainer {
+        let slots = self
+            .providers
+            .iter()
+            .map(|p| Slot {
+                id: p.id,
+                factory: Arc::clone(&p.factory),
+                value: futures::lock::Mutex::new(None),
+            })
+            .collect();
+        ScopeContainer {
+            slots,
+            finishers: Mutex::new(Vec::new()),
+            parent,
+        }
+    }
+}
+
+impl Middleware for RequestScope {
+    fn handle<'t, 'n, 'a>(
+        &'t self,
+        mut req: Request,
+        next: &'n dyn Handler,
+    ) -> BoxFuture<'a, Result<Response>>
+    where
+        't: 'a,
+        'n: 'a,
+        Self: 'a,
+    {
+        let parent = req.extensions_mut().remove::<Arc<ScopeContainer>>();
+        let container = Arc::new(self.container(parent));
+        let _ = req.extensions_mut().insert(Arc::clone(&container));
+
+        Box::pin(async move {
+            let ret = next.handle(req).await;
+
+            let success = match ret {
+                Ok(ref res) => !(res.status().is_client_error() || res.status().is_server_error()),
+                Err(_) => false,
+            };
+
+            let finishers = match container.finishers.lock() {
+                Ok(mut guard) => mem::take(&mut *guard),
+                Err(_) => Vec::new(),
+            };
+
+            let mut ret = ret;
+            for finish in finishers.into_iter().rev() {
+                if let Err(err) = finish(success).await {
+                    tracing::error!("failed to finish request-scoped value: {:?}", err);
+                    if ret.is_ok() {
+                        ret = Err(err);
+                    }
+                }
+            }
+            ret
+        })
+    }
+
+    fn check_state(&self, scope: &mut StateScope, next: &dyn Handler) {
+        let ids: Vec<TypeId> = self.providers.iter().map(|p| p.id).collect();
+        scope.provide_ids(&ids, |scope| next.check_state(scope))
+    }
+}
+
+/// Returns the request-scoped value of `T`, or `None` if no provider is registered.
+pub(crate) async fn resolve<T>(req: &Request) -> Result<Option<Arc<T>>>
+where
+    T: Send + Sync + 'static,
+{
+    let mut cur = req.extensions().get::<Arc<ScopeContainer>>();
+    while let Some(container) = cur {
+        if let Some(slot) = container.slots.iter().find(|s| s.id == TypeId::of::<T>()) {
+            let value = container.get_or_create(slot, req).await?;
+            return Ok(value.downcast::<T>().ok());
+        }
+        cur = container.parent.as_ref();
+    }
+    Ok(None)
+}
+
+/// Looks up `S` in the request state, the injected state and then the request scope.
+///
+/// `T` is the handler or middleware reported in the error.
+pub(crate) async fn resolve_state<S, T>(req: &Request) -> Result<Arc<S>>
+where
+    S: Send + Sync + 'static,
+    T: ?Sized,
+{
+    if let Some(s) = req.state::<S>() {
+        return Ok(s);
+    }
+    match resolve::<S>(req).await? {
+        Some(s) => Ok(s),
+        None => Err(StateError::new::<S>().with_target::<T>().into()),
+    }
+}
+
+impl ScopeContainer {
+    async fn get_or_create(&self, slot: &Slot, req: &Request) -> Result<Value> {
+        let mut value = slot.value.lock().await;
+        if let Some(ref v) = *value {
+            return Ok(Arc::clone(v));
+        }
+
+        let (v, finish) = (slot.factory)(req).await?;
+        if let Ok(mut finishers) = self.finishers.lock() {
+            finishers.push(finish);
+        }
+        *value = Some(Arc::clone(&v));
+        Ok(v)
+    }
+}
+
+#[test]
+fn request_scope() {
+    use crate::functional::{handler, ref_handler};
+    use crate::http::StatusCode;
+    use crate::state;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use futures::executor::block_on;
+
+    static COMMITTED: AtomicUsize = AtomicUsize::new(0);
+    static ROLLED_BACK: AtomicUsize = AtomicUsize::new(0);
+
+    struct Tx;
+
+    #[async_trait]
+    impl RequestScoped for Tx {
+        async fn finish(self: Arc<Self>, success: bool) -> Result<()> {
+            let counter = if success { &COMMITTED } else { &ROLLED_BACK };
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    async fn ok(_: &Tx, _: Request) -> StatusCode {
+        StatusCode::OK
+    }
+
+    async fn fail(_: &Tx, _: Request) -> StatusCode {
+        StatusCode::BAD_REQUEST
+    }
+
+    async fn untouched(_: Request) {}
+
+    let mut scope = RequestScope::new();
+    scope.provide(|_| async { Ok(Tx) });
+    let scope = Arc::new(scope);
+
+    let call = |h: &dyn Handler| {
+        let req = Request::from_hyper(HyperRequest::default());
+        block_on(Middleware::handle(&*scope, req, h)).unwrap()
+    };
+
+    assert_eq!(call(&ref_handler(ok)).status(), StatusCode::OK);
+    assert_eq!(call(&ref_handler(fail)).status(), StatusCode::BAD_REQUEST);
+    call(&handler(untouched));
+
+    assert_eq!(COMMITTED.load(Ordering::SeqCst), 1);
+    assert_eq!(ROLLED_BACK.load(Ordering::SeqCst), 1);
+
+    let mut scope = RequestScope::new();
+    scope.provide(|_| async { Ok(Tx) });
+    assert!(state::verify(&ref_handler(ok)).is_err());
+    assert!(state::verify(&ref_handler(ok).wrap(scope)).is_ok());
+}
@@ -1,42 +1,389 @@
+use crate::error::error_response;
 use crate::handler::Handler;
+use crate::http::Extensions;
 use crate::internal_prelude::*;
+use crate::middleware::CatchPanic;
+use crate::state::{self, RequestState, StateError};
 
-use std::net::{TcpListener, ToSocketAddrs};
-use std::sync::Arc;
+use std::any::TypeId;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::error::Error as StdError;
+use std::io;
+use std::mem;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
+use std::time::Duration;
 
-use futures::future;
+use futures::channel::oneshot;
+use futures::future::{self, Either, FutureExt};
+use hyper::server::accept::Accept;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::task::JoinHandle;
+
+mod builder;
+pub use self::builder::ServerBuilder;
+
+mod conn;
+use self::conn::Connection;
+
+mod limit;
+use self::limit::{Admission, Limits};
+
+mod listener;
+pub use self::listener::Listener;
+
+#[cfg(feature = "tls")]
+mod tls;
+pub use self::conn::{ConnectionInfo, TlsInfo};
+#[cfg(feature = "tls")]
+pub use self::tls::TlsConfig;
+
+type StateInserter = Box<dyn Fn(&mut Extensions) + Send + Sync>;
+
+type StartupHook = Box<dyn FnOnce() -> BoxFuture<'static, Result<Option<StateInserter>>> + Send>;
+
+type ShutdownHook = Box<dyn FnOnce() -> BoxFuture<'static, Result<()>> + Send>;
+
+type Bind = Box<dyn FnOnce(&ServerBuilder) -> Result<Listener> + Send>;
+
+struct ListenerSpec {
+    bind: Bind,
+    handler: Option<Arc<dyn Handler>>,
+}
+
+type ErrorResponder = Arc<dyn Fn(Error) -> Response + Send + Sync>;
 
 struct ServerInner {
-    handler: Box<dyn Handler>,
+    handler: Arc<dyn Handler>,
+    states: Arc<Vec<StateInserter>>,
+    error_responder: ErrorResponder,
+    catch_panic: bool,
+    limits: Limits,
 }
 
-async fn hyper_call(server: &ServerInner, req: HyperRequest) -> Result<HyperResponse> {
-    let req = Request::from_hyper(req);
-    let res = server.handler.handle(req).await?;
-    Ok(res.into_hyper())
+async fn hyper_call(
+    server: &ServerInner,
+    conn: Option<ConnectionInfo>,
+    req: HyperRequest,
+) -> HyperResponse {
+    let mut req = Request::from_hyper(req);
+    if let Some(conn) = conn {
+        let _ = req.extensions_mut().insert(conn);
+    }
+    for insert in server.states.iter() {
+        insert(req.extensions_mut());
+    }
+    let ret = if server.catch_panic {
+        CatchPanic.handle(req, &*server.handler).await
+    } else {
+        server.handler.handle(req).await
+    };
+    let res = match ret {
+        Ok(res) => res,
+        Err(err) => (server.error_responder)(err),
+    };
+    res.into_hyper()
 }
 
 pub struct Server {
-    inner: Arc<ServerInner>,
+    handler: Arc<dyn Handler>,
+    listeners: Vec<ListenerSpec>,
+    startup_hooks: Vec<StartupHook>,
+    startup_states: Vec<TypeId>,
+    shutdown_hooks: Vec<ShutdownHook>,
+    shutdown_timeout: Duration,
+    builder: ServerBuilder,
+    error_responder: ErrorResponder,
+    catch_panic: bool,
 }
 
 impl Server {
+    const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
     pub fn new(handler: Box<dyn Handler>) -> Self {
         Self {
-            inner: Arc::new(ServerInner { handler }),
+            handler: Arc::from(handler),
+            listeners: Vec::new(),
+            startup_hooks: Vec::new(),
+            startup_states: Vec::new(),
+            shutdown_hooks: Vec::new(),
+            shutdown_timeout: Self::DEFAULT_SHUTDOWN_TIMEOUT,
+            builder: ServerBuilder::default(),
+            error_responder: Arc::new(error_response),
+            catch_panic: true,
         }
     }
 
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::new()
+    }
+
+    /// Sets how long in-flight requests may run after the shutdown signal.
+    ///
+    /// Connections still open after the timeout are closed forcibly.
+    /// The default is 30 seconds.
+    pub fn shutdown_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Sets the function converting errors of handlers into responses.
+    ///
+    /// The default is [`error_response`].
+    pub fn error_responder<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(Error) -> Response + Send + Sync + 'static,
+    {
+        self.error_responder = Arc::new(f);
+        self
+    }
+
+    /// Sets whether panics of handlers are caught by [`CatchPanic`]. The default is true.
+    ///
+    /// A caught panic is passed to the error responder as a [`PanicError`](crate::error::PanicError).
+    /// Otherwise a panic closes the connection.
+    pub fn catch_panic(&mut self, enabled: bool) -> &mut Self {
+        self.catch_panic = enabled;
+        self
+    }
+
+    /// Adds a hook which runs before binding.
+    ///
+    /// Hooks run in the order they are added. An error aborts [`Server::run`].
+    pub fn on_startup<F, U>(&mut self, f: F) -> &mut Self
+    where
+        F: FnOnce() -> U + Send + 'static,
+        U: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.startup_hooks.push(Box::new(move || {
+            Box::pin(async move {
+                f().await?;
+                Ok(None)
+            })
+        }));
+        self
+    }
+
+    /// Adds a startup hook which builds a state.
+    ///
+    /// The state is attached to every request, see [`Request::state`].
+    pub fn startup_state<S, F, U>(&mut self, f: F) -> &mut Self
+    where
+        S: Send + Sync + 'static,
+        F: FnOnce() -> U + Send + 'static,
+        U: Future<Output = Result<Arc<S>>> + Send + 'static,
+    {
+        self.startup_states.push(TypeId::of::<S>());
+        self.startup_hooks.push(Box::new(move || {
+            Box::pin(async move {
+                let state = f().await?;
+                let insert: StateInserter = Box::new(move |extensions| {
+                    extensions.insert(RequestState(Arc::clone(&state)));
+                });
+                Ok(Some(insert))
+            })
+        }));
+        self
+    }
+
+    /// Adds a hook which runs after the server stops.
+    ///
+    /// Hooks run in the reverse order they are added. All of them run even if some fail.
+    pub fn on_shutdown<F, U>(&mut self, f: F) -> &mut Self
+    where
+        F: FnOnce() -> U + Send + 'static,
+        U: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.shutdown_hooks.push(Box::new(move || Box::pin(f())));
+        self
+    }
+
+    /// Adds a TCP listener bound after the startup hooks run.
+    ///
+    /// The listener serves `handler`, or the root handler of the server if `None`.
+    pub fn listen<A>(&mut self, addr: A, handler: Option<Box<dyn Handler>>) -> &mut Self
+    where
+        A: ToSocketAddrs + Send + 'static,
+    {
+        self.listeners.push(ListenerSpec {
+            bind: Box::new(move |builder| Ok(Listener::Tcp(builder.bind(addr)?))),
+            handler: handler.map(Arc::from),
+        });
+        self
+    }
+
+    /// Adds a bound listener, see [`Server::listen`].
+    pub fn listen_on(
+        &mut self,
+        listener: impl Into<Listener>,
+        handler: Option<Box<dyn Handler>>,
+    ) -> &mut Self {
+        let listener = listener.into();
+        self.listeners.push(ListenerSpec {
+            bind: Box::new(move |_| Ok(listener)),
+            handler: handler.map(Arc::from),
+        });
+        self
+    }
+
+    /// Checks the states required by the handlers of the server, see [`state::verify`].
+    ///
+    /// States built by [`Server::startup_state`] count as provided.
+    pub fn verify_state(&self) -> Result<(), StateError> {
+        let handlers = self
+            .listeners
+            .iter()
+            .filter_map(|spec| spec.handler.as_ref());
+        for h in std::iter::once(&self.handler).chain(handlers) {
+            state::verify_provided(&**h, &self.startup_states)?;
+        }
+        Ok(())
+    }
+
+    /// The part of the server used by the `Service` impls, without startup states and limits
+    fn service_inner(&self) -> ServerInner {
+        ServerInner {
+            handler: Arc::clone(&self.handler),
+            states: Arc::default(),
+            error_responder: Arc::clone(&self.error_responder),
+            catch_panic: self.catch_panic,
+            limits: Limits::default(),
+        }
+    }
+
+    async fn startup(&mut self) -> Result<Arc<Vec<StateInserter>>> {
+        let mut states = Vec::new();
+        for hook in self.startup_hooks.drain(..) {
+            if let Some(insert) = hook().await? {
+                states.push(insert);
+            }
+        }
+        Ok(Arc::new(states))
+    }
+
     pub async fn run(self, addr: impl ToSocketAddrs) -> Result<()> {
-        let listener = TcpListener::bind(&addr)?;
-        let builder = HyperServer::from_tcp(listener)?;
-        let hyper_server = builder.serve(self);
-        hyper_server.await?;
+        self.run_with_shutdown(addr, future::pending()).await?;
         Ok(())
     }
+
+    /// Runs the server until `signal` completes, then shuts it down gracefully.
+    ///
+    /// The server stops accepting connections and waits for in-flight requests
+    /// up to [`Server::shutdown_timeout`]. Shutdown hooks run after that.
+    /// Listeners added by [`Server::listen`] are served as well.
+    ///
+    /// Returns the number of connections aborted at the timeout.
+    ///
+    /// `addr` is resolved before the startup hooks run and bound after them.
+    pub async fn run_with_shutdown(
+        mut self,
+        addr: impl ToSocketAddrs,
+        signal: impl Future<Output = ()> + Send + 'static,
+    ) -> Result<usize> {
+        let addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+        self.listeners.push(ListenerSpec {
+            bind: Box::new(move |builder| Ok(Listener::Tcp(builder.bind(&*addrs)?))),
+            handler: None,
+        });
+        self.run_listeners(signal).await
+    }
+
+    /// Runs the server on `listener` until `signal` completes.
+    ///
+    /// See [`Server::run_with_shutdown`].
+    pub async fn run_on(
+        mut self,
+        listener: impl Into<Listener>,
+        signal: impl Future<Output = ()> + Send + 'static,
+    ) -> Result<usize> {
+        self.listen_on(listener, None);
+        self.run_listeners(signal).await
+    }
+
+    /// Runs the listeners added by [`Server::listen`] until `signal` completes.
+    ///
+    /// All listeners share the startup states and the graceful shutdown.
+    pub async fn run_listeners(
+        mut self,
+        signal: impl Future<Output = ()> + Send + 'static,
+    ) -> Result<usize> {
+        if self.listeners.is_empty() {
+            anyhow::bail!("no listener to serve");
+        }
+
+        let states = self.startup().await?;
+        let ret = self.serve_all(states, signal).await;
+        let shutdown_ret = shutdown(self.shutdown_hooks).await;
+
+        match (ret, shutdown_ret) {
+            (Ok(aborted), Ok(())) => Ok(aborted),
+            (Err(e), _) | (_, Err(e)) => Err(e),
+        }
+    }
+
+    async fn serve_all(
+        &mut self,
+        states: Arc<Vec<StateInserter>>,
+        signal: impl Future<Output = ()> + Send + 'static,
+    ) -> Result<usize> {
+        let mut bound = Vec::with_capacity(self.listeners.len());
+        for spec in mem::take(&mut self.listeners) {
+            let listener = (spec.bind)(&self.builder)?;
+            let handler = spec.handler.unwrap_or_else(|| Arc::clone(&self.handler));
+            bound.push((listener, handler));
+        }
+
+        // the first failing listener shuts the others down gracefully
+        let (fail_tx, fail_rx) = oneshot::channel::<()>();
+        let fail_tx = Mutex::new(Some(fail_tx));
+        let fail = || {
+            if let Some(tx) = fail_tx.lock().ok().and_then(|mut tx| tx.take()) {
+                let _ = tx.send(());
+            }
+        };
+
+        let limits = self.builder.limits();
+        let signal = future::select(signal.boxed(), fail_rx)
+            .map(|_| ())
+            .boxed()
+            .shared();
+        let serves = bound.into_iter().map(|(listener, handler)| {
+            let inner = ServerInner {
+                handler,
+                states: Arc::clone(&states),
+                error_responder: Arc::clone(&self.error_responder),
+                catch_panic: self.catch_panic,
+                limits: limits.clone(),
+            };
+            let serve = serve(
+                inner,
+                &self.builder,
+                listener,
+                signal.clone(),
+                self.shutdown_timeout,
+            );
+            serve.inspect(|ret| {
+                if ret.is_err() {
+                    fail()
+                }
+            })
+        });
+
+        let mut aborted = 0;
+        for ret in future::join_all(serves).await {
+            aborted += ret?;
+        }
+        Ok(aborted)
+    }
 }
 
+/// Serves requests with the handler, the error responder and [`Server::catch_panic`].
+///
+/// Hooks, listeners and limits only apply to [`Server::run`] and its variants.
 impl hyper::service::Service<HyperRequest> for Server {
     type Response = HyperResponse;
     type Error = crate::error::Error;
@@ -47,8 +394,8 @@ impl hyper::service::Service<HyperRequest> for Server {
     }
 
     fn call(&mut self, req: HyperRequest) -> Self::Future {
-        let inner = Arc::clone(&self.inner);
-        Box::pin(async move { hyper_call(&*inner, req).await })
+        let inner = self.service_inner();
+        Box::pin(async move { Ok(hyper_call(&inner, None, req).await) })
     }
 }
 
@@ -62,8 +409,562 @@ impl hyper::service::Service<&'_ hyper::server::conn::AddrStream> for Server {
     }
 
     fn call(&mut self, _req: &'_ hyper::server::conn::AddrStream) -> Self::Future {
-        future::ready(Ok(Self {
-            inner: Arc::clone(&self.inner),
+        future::ready(Ok(Server {
+            handler: Arc::clone(&self.handler),
+            listeners: Vec::new(),
+            startup_hooks: Vec::new(),
+            startup_states: Vec::new(),
+            shutdown_hooks: Vec::new(),
+            shutdown_timeout: self.shutdown_timeout,
+            builder: ServerBuilder::default(),
+            error_responder: Arc::clone(&self.error_responder),
+            catch_panic: self.catch_panic,
         }))
     }
 }
+
+async fn serve(
+    inner: ServerInner,
+    builder: &ServerBuilder,
+    listener: Listener,
+    signal: impl Future<Output = ()> + Send + 'static,
+    timeout: Duration,
+) -> Result<usize> {
+    match listener {
+        Listener::Tcp(listener) => {
+            let incoming = builder.tcp_incoming(listener)?;
+            serve_transport(incoming, inner, builder, signal, timeout).await
+        }
+        #[cfg(unix)]
+        Listener::Unix(listener) => {
+            let incoming = self::listener::UnixIncoming::new(listener)?;
+            serve_transport(incoming, inner, builder, signal, timeout).await
+        }
+    }
+}
+
+/// Serves `incoming`, wrapped by TLS if configured
+async fn serve_transport<I>(
+    incoming: I,
+    inner: ServerInner,
+    builder: &ServerBuilder,
+    signal: impl Future<Output = ()> + Send + 'static,
+    timeout: Duration,
+) -> Result<usize>
+where
+    I: Accept<Error = io::Error> + Unpin + Send + 'static,
+    I::Conn: Connection + AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    #[cfg(feature = "tls")]
+    if let Some(acceptor) = builder.tls_acceptor() {
+        // connections are admitted before their handshakes
+        let limit = inner.limits.connections.clone();
+        let incoming = self::tls::TlsIncoming::new(incoming, acceptor, limit);
+        return serve_incoming(incoming, inner, builder, signal, timeout, false).await;
+    }
+
+    serve_incoming(incoming, inner, builder, signal, timeout, true).await
+}
+
+async fn serve_incoming<I>(
+    incoming: I,
+    inner: ServerInner,
+    builder: &ServerBuilder,
+    signal: impl Future<Output = ()> + Send + 'static,
+    timeout: Duration,
+    admit: bool,
+) -> Result<usize>
+where
+    I: Accept,
+    I::Conn: Connection + AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    I::Error: Into<Box<dyn StdError + Send + Sync>>,
+{
+    let tasks = TaskTracker::default();
+    let hyper_builder = HyperServer::builder(incoming).executor(tasks.clone());
+    let hyper_builder = builder.configure(hyper_builder);
+    let service = ServerService {
+        inner: Arc::new(inner),
+        conn: None,
+        connections: Arc::new(AtomicUsize::new(0)),
+        _guard: None,
+        admit,
+        admission: None,
+        _permit: None,
+    };
+    let connections = Arc::clone(&service.connections);
+
+    let (tx, rx) = oneshot::channel::<()>();
+    let hyper_server = hyper_builder.serve(service);
+    let hyper_server = Box::pin(hyper_server.with_graceful_shutdown(async move {
+        signal.await;
+        let _ = tx.send(());
+    }));
+
+    let deadline = Box::pin(async move {
+        match rx.await {
+            Ok(()) => tokio::time::sleep(timeout).await,
+            Err(_) => future::pending().await,
+        }
+    });
+
+    match future::select(hyper_server, deadline).await {
+        Either::Left((ret, _)) => {
+            ret?;
+            Ok(0)
+        }
+        Either::Right(((), hyper_server)) => {
+            drop(hyper_server);
+            let aborted = connections.load(Ordering::SeqCst);
+            tasks.abort_all();
+            if aborted > 0 {
+                tracing::warn!("aborted {} connections after shutdown timeout", aborted);
+            }
+            Ok(aborted)
+        }
+    }
+}
+
+/// Spawns the tasks of hyper, which are aborted if they outlive the shutdown timeout
+#[derive(Clone, Default)]
+struct TaskTracker {
+    tasks: Arc<Mutex<TaskMap>>,
+}
+
+#[derive(Default)]
+struct TaskMap {
+    next_id: u64,
+    handles: HashMap<u64, JoinHandle<()>>,
+}
+
+impl TaskTracker {
+    fn abort_all(&self) {
+        if let Ok(mut map) = self.tasks.lock() {
+            for (_, handle) in map.handles.drain() {
+                handle.abort();
+            }
+        }
+    }
+}
+
+impl<F> hyper::rt::Executor<F> for TaskTracker
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    fn execute(&self, fut: F) {
+        let mut map = match self.tasks.lock() {
+            Ok(guard) => guard,
+            Err(_) => return,
+        };
+        let id = map.next_id;
+        map.next_id += 1;
+
+        let tasks = Arc::clone(&self.tasks);
+        let handle = tokio::spawn(async move {
+            fut.await;
+            if let Ok(mut map) = tasks.lock() {
+                map.handles.remove(&id);
+            }
+        });
+        map.handles.insert(id, handle);
+    }
+}
+
+/// Counts a live connection until dropped
+struct ConnectionGuard {
+    connections: Arc<AtomicUsize>,
+}
+
+impl ConnectionGuard {
+    fn new(connections: &Arc<AtomicUsize>) -> Self {
+        connections.fetch_add(1, Ordering::SeqCst);
+        Self {
+            connections: Arc::clone(connections),
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+async fn shutdown(hooks: Vec<ShutdownHook>) -> Result<()> {
+    let mut ret = Ok(());
+    for hook in hooks.into_iter().rev() {
+        if let Err(err) = hook().await {
+            tracing::error!("shutdown hook failed: {:?}", err);
+            if ret.is_ok() {
+                ret = Err(err);
+            }
+        }
+    }
+    ret
+}
+
+struct ServerService {
+    inner: Arc<ServerInner>,
+    conn: Option<ConnectionInfo>,
+    connections: Arc<AtomicUsize>,
+    _guard: Option<ConnectionGuard>,
+    /// whether the make service admits connections, which is false if the incoming does
+    admit: bool,
+    /// reserved by `poll_ready` of the make service, held by a connection service
+    admission: Option<Admission>,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl hyper::service::Service<HyperRequest> for ServerService {
+    type Response = HyperResponse;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: HyperRequest) -> Self::Future {
+        let permit = match self.inner.limits.requests {
+            Some(ref limit) => match limit.try_acquire() {
+                Some(permit) => Some(permit),
+                None => return Box::pin(future::ready(Ok(limit.overloaded().into_hyper()))),
+            },
+            None => None,
+        };
+        let inner = Arc::clone(&self.inner);
+        let conn = self.conn.clone();
+        Box::pin(async move {
+            let res = hyper_call(&inner, conn, req).await;
+            drop(permit);
+            Ok(res)
+        })
+    }
+}
+
+impl<C> hyper::service::Service<&'_ C> for ServerService
+where
+    C: Connection,
+{
+    type Response = Self;
+    type Error = anyhow::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if let (true, Some(limit)) = (self.admit, &self.inner.limits.connections) {
+            if self.admission.is_none() {
+                self.admission = Some(futures::ready!(limit.poll_admit(cx)));
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, stream: &'_ C) -> Self::Future {
+        let inner = Arc::clone(&self.inner);
+        let conn = stream.info();
+        let connections = Arc::clone(&self.connections);
+        let guard = ConnectionGuard::new(&self.connections);
+        let admit = self.admit;
+        let admission = self.admission.take();
+        Box::pin(async move {
+            let permit = match inner.limits.connections {
+                Some(ref limit) => Some(limit.acquire().await?),
+                None => None,
+            };
+            Ok(Self {
+                inner,
+                conn: Some(conn),
+                connections,
+                _guard: Some(guard),
+                admit,
+                admission,
+                _permit: permit,
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn graceful_shutdown() {
+    use crate::functional::handler;
+
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpStream;
+
+    const ADDR: &str = "127.0.0.1:38741";
+
+    async fn slow(_: Request) {
+        tokio::time::sleep(Duration::from_secs(10)).await
+    }
+
+    let (tx, rx) = oneshot::channel::<()>();
+    let mut server = handler(slow).into_server();
+    server.shutdown_timeout(Duration::from_millis(100));
+    let task = tokio::spawn(server.run_with_shutdown(ADDR, async {
+        let _ = rx.await;
+    }));
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let mut stream = TcpStream::connect(ADDR).await.unwrap();
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    tx.send(()).unwrap();
+    let aborted = task.await.unwrap().unwrap();
+    assert_eq!(aborted, 1);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn connection_info() {
+    use crate::functional::handler;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    const ADDR: &str = "127.0.0.1:38742";
+
+    async fn peer(req: Request) -> String {
+        match req.remote_addr() {
+            Some(addr) => addr.to_string(),
+            None => String::new(),
+        }
+    }
+
+    let (tx, rx) = oneshot::channel::<()>();
+    let task = tokio::spawn(handler(peer).into_server().run_with_shutdown(ADDR, async {
+        let _ = rx.await;
+    }));
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let mut stream = TcpStream::connect(ADDR).await.unwrap();
+    let local = stream.local_addr().unwrap();
+    let req = b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+    stream.write_all(req).await.unwrap();
+    let mut res = String::new();
+    stream.read_to_string(&mut res).await.unwrap();
+    assert!(res.ends_with(&local.to_string()));
+
+    tx.send(()).unwrap();
+    assert_eq!(task.await.unwrap().unwrap(), 0);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn multiple_listeners() {
+    use crate::functional::handler;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    const PUBLIC: &str = "127.0.0.1:38744";
+    const ADMIN: &str = "127.0.0.1:38745";
+
+    async fn get(addr: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let req = b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+        stream.write_all(req).await.unwrap();
+        let mut res = String::new();
+        stream.read_to_string(&mut res).await.unwrap();
+        res
+    }
+
+    let mut server = handler(|_: Request| async { "public" }).into_server();
+    let admin = handler(|_: Request| async { "admin" }).boxed();
+    server.listen(PUBLIC, None).listen(ADMIN, Some(admin));
+
+    let (tx, rx) = oneshot::channel::<()>();
+    let task = tokio::spawn(server.run_listeners(async {
+        let _ = rx.await;
+    }));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    assert!(get(PUBLIC).await.ends_with("public"));
+    assert!(get(ADMIN).await.ends_with("admin"));
+
+    tx.send(()).unwrap();
+    assert_eq!(task.await.unwrap().unwrap(), 0);
+}
+
+#[cfg(all(test, unix))]
+#[tokio::test]
+async fn failing_listener() {
+    use crate::functional::handler;
+
+    use socket2::{Domain, SockAddr, Socket, Type};
+
+    // a socket which is bound but not listening fails to accept
+    let path = std::env::temp_dir().join(format!("nuclear-fail-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let socket = Socket::new(Domain::UNIX, Type::STREAM, None).unwrap();
+    socket.bind(&SockAddr::unix(&path).unwrap()).unwrap();
+    let broken = Listener::Unix(socket.into());
+
+    let mut server = handler(|_: Request| async { "ok" }).into_server();
+    server.listen("127.0.0.1:0", None).listen_on(broken, None);
+
+    // the healthy listener shuts down instead of serving forever
+    let wait = Duration::from_secs(5);
+    let ret = tokio::time::timeout(wait, server.run_listeners(future::pending()));
+    assert!(ret.await.unwrap().is_err());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn lifecycle_hooks() {
+    use crate::functional::handler;
+
+    use std::net::SocketAddr;
+
+    static TRACE: Mutex<Vec<&'static str>> = Mutex::new(Vec::new());
+
+    fn trace(event: &'static str) {
+        TRACE.lock().unwrap().push(event);
+    }
+
+    fn take_trace() -> Vec<&'static str> {
+        mem::take(&mut *TRACE.lock().unwrap())
+    }
+
+    /// Records when the server resolves the address to bind
+    struct Probe;
+
+    impl ToSocketAddrs for Probe {
+        type Iter = std::option::IntoIter<SocketAddr>;
+
+        fn to_socket_addrs(&self) -> io::Result<Self::Iter> {
+            trace("bind");
+            Ok(Some(SocketAddr::from(([127, 0, 0, 1], 0))).into_iter())
+        }
+    }
+
+    async fn ok(_: Request) {}
+
+    let mut server = handler(ok).into_server();
+    server
+        .on_startup(|| async {
+            trace("start 1");
+            Ok(())
+        })
+        .on_startup(|| async {
+            trace("start 2");
+            Ok(())
+        })
+        .on_shutdown(|| async {
+            trace("stop 1");
+            Ok(())
+        })
+        .on_shutdown(|| async {
+            trace("stop 2");
+            anyhow::bail!("stop 2 failed")
+        })
+        .on_shutdown(|| async {
+            trace("stop 3");
+            Ok(())
+        });
+    server.listen(Probe, None);
+    let err = server.run_listeners(async {}).await.unwrap_err();
+    assert_eq!(err.to_string(), "stop 2 failed");
+    assert_eq!(
+        take_trace(),
+        ["start 1", "start 2", "bind", "stop 3", "stop 2", "stop 1"]
+    );
+
+    let mut server = handler(ok).into_server();
+    server
+        .on_startup(|| async {
+            trace("start 1");
+            anyhow::bail!("start 1 failed")
+        })
+        .on_startup(|| async {
+            trace("start 2");
+            Ok(())
+        })
+        .on_shutdown(|| async {
+            trace("stop 1");
+            Ok(())
+        });
+    server.listen(Probe, None);
+    let err = server.run_listeners(async {}).await.unwrap_err();
+    assert_eq!(err.to_string(), "start 1 failed");
+    assert_eq!(take_trace(), ["start 1"]);
+
+    // borrowed addresses are resolved up front
+    let addr = String::from("127.0.0.1:0");
+    let server = handler(ok).into_server();
+    assert_eq!(server.run_with_shutdown(&addr, async {}).await.unwrap(), 0);
+}
+
+#[test]
+fn verify_startup_state() {
+    use crate::extract::State;
+    use crate::functional::handler;
+
+    struct Db;
+
+    async fn query(_: State<Db>) {}
+
+    let server = handler(query).into_server();
+    let err = server.verify_state().unwrap_err();
+    assert_eq!(err.state(), std::any::type_name::<Db>());
+
+    let mut server = handler(query).into_server();
+    server.startup_state(|| async { Ok(Arc::new(Db)) });
+    assert!(server.verify_state().is_ok());
+
+    let mut server = handler(query).into_server();
+    server.startup_state(|| async { Ok(Arc::new(Db)) });
+    server.listen("127.0.0.1:0", Some(handler(query).boxed()));
+    assert!(server.verify_state().is_ok());
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn hyper_service() {
+    use crate::functional::handler;
+
+    use hyper::server::conn::AddrStream;
+    use hyper::service::Service;
+
+    fn assert_make_service<S: for<'a> Service<&'a AddrStream>>(_: &S) {}
+
+    async fn hello(_: Request) -> &'static str {
+        "hello"
+    }
+
+    let mut server = handler(hello).into_server();
+    assert_make_service(&server);
+    let res = server.call(HyperRequest::default()).await.unwrap();
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    assert_eq!(body, "hello");
+    // caught panics go through the error responder
+    async fn boom(_: Request) {
+        panic!("boom")
+    }
+
+    let mut server = handler(boom).into_server();
+    server.error_responder(|err| {
+        assert!(err.is::<crate::error::PanicError>());
+        crate::http::StatusCode::SERVICE_UNAVAILABLE.into()
+    });
+    let res = server.call(HyperRequest::default()).await.unwrap();
+    assert_eq!(res.status(), crate::http::StatusCode::SERVICE_UNAVAILABLE);
+
+    // so do server errors of extractors
+    struct Db;
+
+    async fn query(_: crate::extract::State<Db>) {}
+
+    let mut server = handler(query).into_server();
+    server.error_responder(|err| {
+        let rejection = err.downcast_ref::<crate::extract::Rejection>().unwrap();
+        assert!(rejection.error().is::<crate::state::StateError>());
+        crate::http::StatusCode::SERVICE_UNAVAILABLE.into()
+    });
+    let res = server.call(HyperRequest::default()).await.unwrap();
+    assert_eq!(res.status(), crate::http::StatusCode::SERVICE_UNAVAILABLE);
+}
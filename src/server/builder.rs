@@ -0,0 +1,251 @@
+use super::limit::{ConnectionLimit, Limits, RequestLimit};
+use super::Server;
+use crate::internal_prelude::*;
+
+use std::io;
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::server::conn::AddrIncoming;
+use socket2::{Domain, Protocol, Socket, Type};
+
+/// Connection options of a [`Server`]
+///
+/// Options left unset use the defaults of hyper.
+#[derive(Debug, Clone, Default)]
+pub struct ServerBuilder {
+    http1_keepalive: Option<bool>,
+    http1_header_read_timeout: Option<Duration>,
+    max_header_size: Option<usize>,
+    http2_stream_window_size: Option<u32>,
+    http2_connection_window_size: Option<u32>,
+    http2_max_concurrent_streams: Option<u32>,
+    http1_only: bool,
+    http2_only: bool,
+    tcp_nodelay: bool,
+    reuse_port: bool,
+    max_connections: Option<usize>,
+    connection_queue: usize,
+    max_requests: Option<usize>,
+    retry_after: Option<Duration>,
+    #[cfg(feature = "tls")]
+    tls: Option<super::TlsConfig>,
+}
+
+impl ServerBuilder {
+    /// The minimum of [`ServerBuilder::max_header_size`] accepted by hyper
+    const MIN_HEADER_SIZE: usize = 8192;
+
+    const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets whether HTTP/1 connections are kept alive. The default is true.
+    pub fn http1_keepalive(&mut self, enabled: bool) -> &mut Self {
+        self.http1_keepalive = Some(enabled);
+        self
+    }
+
+    /// Closes HTTP/1 connections which do not send the request headers in time.
+    pub fn http1_header_read_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.http1_header_read_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the maximum size of the HTTP/1 read buffer, which bounds the request headers.
+    ///
+    /// # Panics
+    /// Panics if `size` is less than 8 KiB.
+    pub fn max_header_size(&mut self, size: usize) -> &mut Self {
+        assert!(
+            size >= Self::MIN_HEADER_SIZE,
+            "max_header_size must be at least {} bytes",
+            Self::MIN_HEADER_SIZE
+        );
+        self.max_header_size = Some(size);
+        self
+    }
+
+    /// Sets the initial HTTP/2 flow control window of each stream.
+    pub fn http2_stream_window_size(&mut self, size: u32) -> &mut Self {
+        self.http2_stream_window_size = Some(size);
+        self
+    }
+
+    /// Sets the initial HTTP/2 flow control window of each connection.
+    pub fn http2_connection_window_size(&mut self, size: u32) -> &mut Self {
+        self.http2_connection_window_size = Some(size);
+        self
+    }
+
+    pub fn http2_max_concurrent_streams(&mut self, max: u32) -> &mut Self {
+        self.http2_max_concurrent_streams = Some(max);
+        self
+    }
+
+    /// Accepts HTTP/1 only.
+    pub fn http1_only(&mut self, enabled: bool) -> &mut Self {
+        self.http1_only = enabled;
+        self
+    }
+
+    /// Accepts HTTP/2 only, which suits load balancers speaking h2c.
+    pub fn http2_only(&mut self, enabled: bool) -> &mut Self {
+        self.http2_only = enabled;
+        self
+    }
+
+    /// Sets `TCP_NODELAY` on accepted connections.
+    pub fn tcp_nodelay(&mut self, enabled: bool) -> &mut Self {
+        self.tcp_nodelay = enabled;
+        self
+    }
+
+    /// Sets `SO_REUSEPORT` on the listener, so that several processes can bind the same port.
+    ///
+    /// This is ignored on platforms other than Unix.
+    pub fn reuse_port(&mut self, enabled: bool) -> &mut Self {
+        self.reuse_port = enabled;
+        self
+    }
+
+    /// Limits the connections served at once, across all listeners.
+    ///
+    /// Listeners stop accepting while the limit and [`ServerBuilder::connection_queue`] are full.
+    pub fn max_connections(&mut self, max: usize) -> &mut Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// Sets how many accepted connections may wait for [`ServerBuilder::max_connections`].
+    ///
+    /// The default is 0.
+    pub fn connection_queue(&mut self, len: usize) -> &mut Self {
+        self.connection_queue = len;
+        self
+    }
+
+    /// Limits the requests handled at once, across all listeners.
+    ///
+    /// Excess requests are answered with 503 and `Retry-After`.
+    pub fn max_requests(&mut self, max: usize) -> &mut Self {
+        self.max_requests = Some(max);
+        self
+    }
+
+    /// Sets the `Retry-After` of shed requests. The default is 1 second.
+    pub fn retry_after(&mut self, delay: Duration) -> &mut Self {
+        self.retry_after = Some(delay);
+        self
+    }
+
+    pub(super) fn limits(&self) -> Limits {
+        let retry_after = self.retry_after.unwrap_or(Self::DEFAULT_RETRY_AFTER);
+        Limits {
+            connections: self
+                .max_connections
+                .map(|max| Arc::new(ConnectionLimit::new(max, self.connection_queue))),
+            requests: self
+                .max_requests
+                .map(|max| Arc::new(RequestLimit::new(max, retry_after))),
+        }
+    }
+
+    /// Serves HTTPS with `config`.
+    ///
+    /// ALPN offers `h2` and `http/1.1` according to [`ServerBuilder::http1_only`]
+    /// and [`ServerBuilder::http2_only`].
+    #[cfg(feature = "tls")]
+    pub fn tls(&mut self, config: super::TlsConfig) -> &mut Self {
+        self.tls = Some(config);
+        self
+    }
+
+    #[cfg(feature = "tls")]
+    pub(super) fn tls_acceptor(&self) -> Option<tokio_rustls::TlsAcceptor> {
+        let config = self.tls.as_ref()?;
+        let mut alpn = Vec::new();
+        if !self.http1_only {
+            alpn.push(b"h2".to_vec());
+        }
+        if !self.http2_only {
+            alpn.push(b"http/1.1".to_vec());
+        }
+        Some(config.acceptor(alpn))
+    }
+
+    pub fn build(&self, handler: Box<dyn Handler>) -> Server {
+        let mut server = Server::new(handler);
+        server.builder = self.clone();
+        server
+    }
+
+    pub(super) fn bind(&self, addr: impl ToSocketAddrs) -> Result<TcpListener> {
+        let mut last_err = None;
+        for addr in addr.to_socket_addrs()? {
+            match self.bind_addr(addr) {
+                Ok(listener) => return Ok(listener),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(match last_err {
+            Some(e) => e.into(),
+            None => anyhow::anyhow!("could not resolve to any addresses"),
+        })
+    }
+
+    fn bind_addr(&self, addr: SocketAddr) -> io::Result<TcpListener> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        #[cfg(unix)]
+        {
+            socket.set_reuse_address(true)?;
+            socket.set_reuse_port(self.reuse_port)?;
+        }
+        socket.bind(&addr.into())?;
+        socket.listen(1024)?;
+        Ok(socket.into())
+    }
+
+    /// Applies the TCP options to `listener`.
+    pub(super) fn tcp_incoming(&self, listener: TcpListener) -> Result<AddrIncoming> {
+        listener.set_nonblocking(true)?;
+        let listener = tokio::net::TcpListener::from_std(listener)?;
+        let mut incoming = AddrIncoming::from_listener(listener)?;
+        incoming.set_nodelay(self.tcp_nodelay);
+        Ok(incoming)
+    }
+
+    pub(super) fn configure<I, E>(
+        &self,
+        mut b: hyper::server::Builder<I, E>,
+    ) -> hyper::server::Builder<I, E> {
+        if let Some(enabled) = self.http1_keepalive {
+            b = b.http1_keepalive(enabled);
+        }
+        if let Some(timeout) = self.http1_header_read_timeout {
+            b = b.http1_header_read_timeout(timeout);
+        }
+        if let Some(size) = self.max_header_size {
+            b = b.http1_max_buf_size(size);
+        }
+        b.http2_initial_stream_window_size(self.http2_stream_window_size)
+            .http2_initial_connection_window_size(self.http2_connection_window_size)
+            .http2_max_concurrent_streams(self.http2_max_concurrent_streams)
+            .http1_only(self.http1_only)
+            .http2_only(self.http2_only)
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn reuse_port() {
+    let mut builder = ServerBuilder::new();
+    builder.reuse_port(true);
+    let first = builder.bind("127.0.0.1:0").unwrap();
+    let addr = first.local_addr().unwrap();
+    assert!(builder.bind(addr).is_ok());
+    assert!(ServerBuilder::new().bind(addr).is_err());
+}
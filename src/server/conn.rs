@@ -0,0 +1,90 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use hyper::server::conn::AddrStream;
+
+/// The connection a request arrives on, attached to every request by [`Server`](super::Server)
+///
+/// See [`Request::connection_info`](crate::request::Request::connection_info).
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    id: u64,
+    peer_addr: Option<SocketAddr>,
+    local_addr: Option<SocketAddr>,
+    tls: Option<TlsInfo>,
+}
+
+/// The TLS session of a connection
+#[derive(Debug, Clone, Default)]
+pub struct TlsInfo {
+    server_name: Option<String>,
+    alpn_protocol: Option<Vec<u8>>,
+}
+
+impl ConnectionInfo {
+    pub(crate) fn new(peer_addr: Option<SocketAddr>, local_addr: Option<SocketAddr>) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            peer_addr,
+            local_addr,
+            tls: None,
+        }
+    }
+
+    #[cfg(feature = "tls")]
+    pub(crate) fn with_tls(mut self, tls: TlsInfo) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// A number identifying the connection within the process
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// The address of the client, or `None` if the transport has no socket address
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    /// The TLS session, or `None` for plain connections
+    pub fn tls(&self) -> Option<&TlsInfo> {
+        self.tls.as_ref()
+    }
+}
+
+impl TlsInfo {
+    #[cfg(feature = "tls")]
+    pub(crate) fn new(server_name: Option<String>, alpn_protocol: Option<Vec<u8>>) -> Self {
+        Self {
+            server_name,
+            alpn_protocol,
+        }
+    }
+
+    /// The server name sent by the client with SNI
+    pub fn server_name(&self) -> Option<&str> {
+        self.server_name.as_deref()
+    }
+
+    /// The protocol negotiated with ALPN, such as `h2`
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.alpn_protocol.as_deref()
+    }
+}
+
+/// A transport stream accepted by [`Server`](super::Server)
+pub(crate) trait Connection {
+    fn info(&self) -> ConnectionInfo;
+}
+
+impl Connection for AddrStream {
+    fn info(&self) -> ConnectionInfo {
+        ConnectionInfo::new(Some(self.remote_addr()), Some(self.local_addr()))
+    }
+}
@@ -0,0 +1,207 @@
+use crate::http::{header, HeaderValue, StatusCode};
+use crate::internal_prelude::*;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// The concurrency limits shared by the listeners of a server
+#[derive(Clone, Default)]
+pub(super) struct Limits {
+    pub(super) connections: Option<Arc<ConnectionLimit>>,
+    pub(super) requests: Option<Arc<RequestLimit>>,
+}
+
+/// Serves at most `max` connections and keeps at most `queue` more waiting
+///
+/// Listeners stop accepting while both are full,
+/// which leaves further connections in the backlog of the socket.
+pub(super) struct ConnectionLimit {
+    admitted: AtomicUsize,
+    max_admitted: usize,
+    permits: Arc<Semaphore>,
+    wakers: Mutex<Vec<Waker>>,
+}
+
+/// A connection counted by [`ConnectionLimit`], which may be still waiting for a permit
+pub(super) struct Admission {
+    limit: Arc<ConnectionLimit>,
+}
+
+impl ConnectionLimit {
+    pub(super) fn new(max: usize, queue: usize) -> Self {
+        Self {
+            admitted: AtomicUsize::new(0),
+            max_admitted: max.saturating_add(queue),
+            permits: Arc::new(Semaphore::new(max)),
+            wakers: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn try_admit(self: &Arc<Self>) -> Option<Admission> {
+        let mut cur = self.admitted.load(Ordering::SeqCst);
+        loop {
+            if cur >= self.max_admitted {
+                return None;
+            }
+            match self.admitted.compare_exchange_weak(
+                cur,
+                cur + 1,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => {
+                    return Some(Admission {
+                        limit: Arc::clone(self),
+                    })
+                }
+                Err(actual) => cur = actual,
+            }
+        }
+    }
+
+    /// Waits until an admitted connection can be served.
+    pub(super) async fn acquire(&self) -> Result<OwnedSemaphorePermit> {
+        let permits = Arc::clone(&self.permits);
+        Ok(permits.acquire_owned().await?)
+    }
+
+    /// Admits a connection, or registers the task to be woken when one closes.
+    pub(super) fn poll_admit(self: &Arc<Self>, cx: &mut Context<'_>) -> Poll<Admission> {
+        if let Some(admission) = self.try_admit() {
+            return Poll::Ready(admission);
+        }
+        if let Ok(mut wakers) = self.wakers.lock() {
+            wakers.push(cx.waker().clone());
+        }
+        // a connection may have closed before the waker is registered
+        match self.try_admit() {
+            Some(admission) => Poll::Ready(admission),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for Admission {
+    fn drop(&mut self) {
+        self.limit.admitted.fetch_sub(1, Ordering::SeqCst);
+        let wakers = match self.limit.wakers.lock() {
+            Ok(mut wakers) => std::mem::take(&mut *wakers),
+            Err(_) => return,
+        };
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+/// Handles at most `max` requests at once and sheds the rest with 503
+pub(super) struct RequestLimit {
+    in_flight: AtomicUsize,
+    max: usize,
+    retry_after: Duration,
+}
+
+/// A request counted by [`RequestLimit`]
+pub(super) struct RequestPermit {
+    limit: Arc<RequestLimit>,
+}
+
+impl RequestLimit {
+    pub(super) fn new(max: usize, retry_after: Duration) -> Self {
+        Self {
+            in_flight: AtomicUsize::new(0),
+            max,
+            retry_after,
+        }
+    }
+
+    pub(super) fn try_acquire(self: &Arc<Self>) -> Option<RequestPermit> {
+        let prev = self.in_flight.fetch_add(1, Ordering::SeqCst);
+        let permit = RequestPermit {
+            limit: Arc::clone(self),
+        };
+        if prev < self.max {
+            Some(permit)
+        } else {
+            None
+        }
+    }
+
+    /// The response to a request exceeding the limit
+    pub(super) fn overloaded(&self) -> Response {
+        let mut res: Response = StatusCode::SERVICE_UNAVAILABLE.into();
+        let secs = self.retry_after.as_secs().max(1);
+        let _ = res
+            .headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from(secs));
+        res
+    }
+}
+
+impl Drop for RequestPermit {
+    fn drop(&mut self) {
+        self.limit.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn request_limit() {
+    let limit = Arc::new(RequestLimit::new(1, Duration::from_secs(5)));
+    let first = limit.try_acquire();
+    assert!(first.is_some());
+    assert!(limit.try_acquire().is_none());
+    drop(first);
+    assert!(limit.try_acquire().is_some());
+
+    let res = limit.overloaded();
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(res.headers()[header::RETRY_AFTER], "5");
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn connection_limit() {
+    use crate::functional::handler;
+    use crate::server::ServerBuilder;
+
+    use futures::channel::oneshot;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    const ADDR: &str = "127.0.0.1:38746";
+    const REQ: &[u8] = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+
+    let mut builder = ServerBuilder::new();
+    builder.max_connections(1).connection_queue(1);
+    let server = builder.build(handler(|_: Request| async { "ok" }).boxed());
+    let (tx, rx) = oneshot::channel::<()>();
+    let task = tokio::spawn(server.run_with_shutdown(ADDR, async {
+        let _ = rx.await;
+    }));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let mut buf = [0; 1024];
+
+    let mut first = TcpStream::connect(ADDR).await.unwrap();
+    first.write_all(REQ).await.unwrap();
+    assert!(first.read(&mut buf).await.unwrap() > 0);
+
+    // queued until the first connection closes
+    let mut second = TcpStream::connect(ADDR).await.unwrap();
+    second.write_all(REQ).await.unwrap();
+    let wait = Duration::from_millis(200);
+    assert!(tokio::time::timeout(wait, second.read(&mut buf))
+        .await
+        .is_err());
+
+    drop(first);
+    assert!(second.read(&mut buf).await.unwrap() > 0);
+
+    drop(second);
+    tx.send(()).unwrap();
+    task.await.unwrap().unwrap();
+}
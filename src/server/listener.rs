@@ -0,0 +1,231 @@
+use crate::internal_prelude::*;
+
+use std::net::TcpListener;
+
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+#[cfg(unix)]
+use std::path::Path;
+
+/// A socket which a [`Server`](super::Server) accepts connections on
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Binds a Unix domain socket at `path`.
+    ///
+    /// The socket file is not removed when the server stops.
+    #[cfg(unix)]
+    pub fn bind_unix(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Listener::Unix(UnixListener::bind(path)?))
+    }
+
+    /// Takes the sockets passed by systemd socket activation.
+    ///
+    /// Returns an empty list if the process is not socket-activated.
+    /// Only the first call takes the sockets, later calls return an empty list.
+    /// The `LISTEN_*` variables are left untouched, since modifying the environment
+    /// is unsound while other threads run. Child processes ignore them anyway,
+    /// as `LISTEN_PID` names this process.
+    ///
+    /// The sockets are marked close-on-exec. Only stream sockets are accepted.
+    #[cfg(unix)]
+    pub fn from_systemd() -> Result<Vec<Self>> {
+        use std::env;
+        use std::os::unix::io::{FromRawFd, RawFd};
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        use socket2::{Socket, Type};
+
+        const LISTEN_FDS_START: RawFd = 3;
+
+        static TAKEN: AtomicBool = AtomicBool::new(false);
+
+        let pid = env::var("LISTEN_PID")
+            .ok()
+            .and_then(|s| s.parse::<u32>().ok());
+        let fds = env::var("LISTEN_FDS")
+            .ok()
+            .and_then(|s| s.parse::<RawFd>().ok());
+
+        let fds = match (pid, fds) {
+            (Some(pid), Some(fds)) if pid == std::process::id() => fds,
+            _ => return Ok(Vec::new()),
+        };
+        if TAKEN.swap(true, Ordering::SeqCst) {
+            return Ok(Vec::new());
+        }
+
+        let mut listeners = Vec::new();
+        for fd in LISTEN_FDS_START..LISTEN_FDS_START + fds {
+            // SAFETY: systemd passes the ownership of these descriptors to this process,
+            // and the flag above makes sure they are taken once
+            #[allow(unsafe_code)]
+            let socket = unsafe { Socket::from_raw_fd(fd) };
+            socket.set_cloexec(true)?;
+            if socket.r#type()? != Type::STREAM {
+                anyhow::bail!("non-stream socket passed by systemd: fd {}", fd);
+            }
+            let addr = socket.local_addr()?;
+            if addr.as_socket().is_some() {
+                listeners.push(Listener::Tcp(socket.into()));
+            } else if addr.is_unix() {
+                listeners.push(Listener::Unix(socket.into()));
+            } else {
+                anyhow::bail!("unsupported socket passed by systemd: fd {}", fd);
+            }
+        }
+        Ok(listeners)
+    }
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Listener::Tcp(listener)
+    }
+}
+
+#[cfg(unix)]
+impl From<UnixListener> for Listener {
+    fn from(listener: UnixListener) -> Self {
+        Listener::Unix(listener)
+    }
+}
+
+#[cfg(unix)]
+pub(super) use self::unix::UnixIncoming;
+
+#[cfg(unix)]
+mod unix {
+    use crate::internal_prelude::*;
+    use crate::server::conn::{Connection, ConnectionInfo};
+
+    use std::io;
+    use std::os::unix::net::UnixListener;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+
+    use hyper::server::accept::Accept;
+    use tokio::net::UnixStream;
+    use tokio::time::Sleep;
+
+    /// Accepts connections on a Unix socket
+    ///
+    /// Like `AddrIncoming` of hyper, errors such as running out of file descriptors
+    /// pause accepting for a second instead of stopping the server.
+    pub(in crate::server) struct UnixIncoming {
+        listener: tokio::net::UnixListener,
+        timeout: Option<Pin<Box<Sleep>>>,
+    }
+
+    impl UnixIncoming {
+        const ERROR_SLEEP: Duration = Duration::from_secs(1);
+
+        pub(in crate::server) fn new(listener: UnixListener) -> Result<Self> {
+            listener.set_nonblocking(true)?;
+            let listener = tokio::net::UnixListener::from_std(listener)?;
+            Ok(Self {
+                listener,
+                timeout: None,
+            })
+        }
+    }
+
+    impl Accept for UnixIncoming {
+        type Conn = UnixStream;
+        type Error = io::Error;
+
+        fn poll_accept(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+            let this = self.get_mut();
+            if let Some(ref mut timeout) = this.timeout {
+                futures::ready!(timeout.as_mut().poll(cx));
+                this.timeout = None;
+            }
+            loop {
+                match this.listener.poll_accept(cx) {
+                    Poll::Ready(Ok((stream, _))) => return Poll::Ready(Some(Ok(stream))),
+                    Poll::Ready(Err(e)) if is_connection_error(&e) => {
+                        tracing::debug!("accept error: {}", e)
+                    }
+                    Poll::Ready(Err(e)) if is_fatal(&e) => return Poll::Ready(Some(Err(e))),
+                    Poll::Ready(Err(e)) => {
+                        tracing::error!("accept error: {}", e);
+                        let mut timeout = Box::pin(tokio::time::sleep(Self::ERROR_SLEEP));
+                        match timeout.as_mut().poll(cx) {
+                            Poll::Ready(()) => continue,
+                            Poll::Pending => {
+                                this.timeout = Some(timeout);
+                                return Poll::Pending;
+                            }
+                        }
+                    }
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+        }
+    }
+
+    fn is_connection_error(e: &io::Error) -> bool {
+        matches!(
+            e.kind(),
+            io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::ConnectionReset
+        )
+    }
+
+    /// Errors meaning that the listener itself is unusable
+    fn is_fatal(e: &io::Error) -> bool {
+        matches!(
+            e.kind(),
+            io::ErrorKind::InvalidInput | io::ErrorKind::Unsupported
+        )
+    }
+
+    impl Connection for UnixStream {
+        fn info(&self) -> ConnectionInfo {
+            ConnectionInfo::new(None, None)
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+#[tokio::test]
+async fn unix_socket() {
+    use crate::functional::handler;
+
+    use std::time::Duration;
+
+    use futures::channel::oneshot;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let path = std::env::temp_dir().join(format!("nuclear-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let listener = Listener::bind_unix(&path).unwrap();
+
+    let h = handler(|req: Request| async move { req.remote_addr().is_none().to_string() });
+    let (tx, rx) = oneshot::channel::<()>();
+    let task = tokio::spawn(h.into_server().run_on(listener, async {
+        let _ = rx.await;
+    }));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+    let req = b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+    stream.write_all(req).await.unwrap();
+    let mut res = String::new();
+    stream.read_to_string(&mut res).await.unwrap();
+    assert!(res.ends_with("true"), "{}", res);
+
+    tx.send(()).unwrap();
+    task.await.unwrap().unwrap();
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,500 @@
+use super::conn::{Connection, ConnectionInfo, TlsInfo};
+use super::limit::{Admission, ConnectionLimit};
+use crate::internal_prelude::*;
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+
+use futures::stream::{FuturesUnordered, StreamExt};
+use hyper::server::accept::Accept;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::{self, CertifiedKey};
+use rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Timeout;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+/// The TLS settings of a [`Server`](super::Server)
+///
+/// The certificate is reloaded from disk when its files are modified,
+/// which a background task checks every [`TlsConfig::reload_interval`] while the server runs.
+#[derive(Clone)]
+pub struct TlsConfig {
+    resolver: Arc<CertResolver>,
+}
+
+struct CertResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    key: RwLock<Arc<CertifiedKey>>,
+    check: Mutex<ReloadCheck>,
+    watching: AtomicBool,
+}
+
+struct ReloadCheck {
+    interval: Duration,
+    modified: Option<SystemTime>,
+}
+
+impl TlsConfig {
+    const DEFAULT_RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+    /// Loads a certificate chain and a private key from PEM files.
+    pub fn from_pem_files(cert_path: impl AsRef<Path>, key_path: impl AsRef<Path>) -> Result<Self> {
+        let cert_path = cert_path.as_ref().to_owned();
+        let key_path = key_path.as_ref().to_owned();
+        let key = load_certified_key(&cert_path, &key_path)?;
+        let modified = modified_time(&cert_path, &key_path);
+        let resolver = CertResolver {
+            cert_path,
+            key_path,
+            key: RwLock::new(Arc::new(key)),
+            check: Mutex::new(ReloadCheck {
+                interval: Self::DEFAULT_RELOAD_INTERVAL,
+                modified,
+            }),
+            watching: AtomicBool::new(false),
+        };
+        Ok(Self {
+            resolver: Arc::new(resolver),
+        })
+    }
+
+    /// Sets how often the files are checked for modification. The default is 10 seconds.
+    pub fn reload_interval(&mut self, interval: Duration) -> &mut Self {
+        if let Ok(mut check) = self.resolver.check.lock() {
+            check.interval = interval;
+        }
+        self
+    }
+
+    /// Reloads the certificate and the key from disk now.
+    ///
+    /// The current certificate is kept if loading fails.
+    pub fn reload(&self) -> Result<()> {
+        self.resolver.reload()
+    }
+
+    /// Creates an acceptor and starts watching the files, which requires a tokio runtime.
+    pub(super) fn acceptor(&self, alpn_protocols: Vec<Vec<u8>>) -> TlsAcceptor {
+        CertResolver::watch(&self.resolver);
+        let resolver: Arc<dyn ResolvesServerCert> = self.resolver.clone();
+        let mut config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(resolver);
+        config.alpn_protocols = alpn_protocols;
+        TlsAcceptor::from(Arc::new(config))
+    }
+}
+
+impl fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsConfig")
+            .field("cert_path", &self.resolver.cert_path)
+            .field("key_path", &self.resolver.key_path)
+            .finish()
+    }
+}
+
+impl CertResolver {
+    fn reload(&self) -> Result<()> {
+        let modified = modified_time(&self.cert_path, &self.key_path);
+        let key = load_certified_key(&self.cert_path, &self.key_path)?;
+        if let Ok(mut guard) = self.key.write() {
+            *guard = Arc::new(key);
+        }
+        if let Ok(mut check) = self.check.lock() {
+            check.modified = modified;
+        }
+        Ok(())
+    }
+
+    fn reload_if_modified(&self) {
+        let modified = match self.check.lock() {
+            Ok(check) => check.modified,
+            Err(_) => return,
+        };
+        if modified_time(&self.cert_path, &self.key_path) == modified {
+            return;
+        }
+        match self.reload() {
+            Ok(()) => tracing::info!("reloaded certificate {}", self.cert_path.display()),
+            Err(e) => tracing::error!("failed to reload certificate: {:?}", e),
+        }
+    }
+
+    fn interval(&self) -> Duration {
+        match self.check.lock() {
+            Ok(check) => check.interval,
+            Err(_) => TlsConfig::DEFAULT_RELOAD_INTERVAL,
+        }
+    }
+
+    /// Spawns the task checking the files, which stops when the config is dropped.
+    ///
+    /// The files are read on the blocking thread pool, away from handshakes.
+    fn watch(this: &Arc<Self>) {
+        if this.watching.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let weak: Weak<Self> = Arc::downgrade(this);
+        tokio::spawn(async move {
+            loop {
+                let interval = match weak.upgrade() {
+                    Some(resolver) => resolver.interval(),
+                    None => break,
+                };
+                tokio::time::sleep(interval).await;
+                let resolver = match weak.upgrade() {
+                    Some(resolver) => resolver,
+                    None => break,
+                };
+                let check = tokio::task::spawn_blocking(move || resolver.reload_if_modified());
+                if check.await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.key.read().ok().map(|key| Arc::clone(&key))
+    }
+}
+
+fn modified_time(cert_path: &Path, key_path: &Path) -> Option<SystemTime> {
+    let cert = fs::metadata(cert_path).and_then(|m| m.modified()).ok()?;
+    let key = fs::metadata(key_path).and_then(|m| m.modified()).ok()?;
+    Some(cert.max(key))
+}
+
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey> {
+    let mut reader = BufReader::new(File::open(cert_path)?);
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut reader)?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certs.is_empty() {
+        anyhow::bail!("no certificate found in {}", cert_path.display());
+    }
+
+    let mut reader = BufReader::new(File::open(key_path)?);
+    let key = loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => break PrivateKey(key),
+            Some(_) => continue,
+            None => anyhow::bail!("no private key found in {}", key_path.display()),
+        }
+    };
+    let key = sign::any_supported_type(&key)?;
+
+    Ok(CertifiedKey::new(certs, key))
+}
+
+/// Accepts connections and runs TLS handshakes concurrently
+///
+/// Connections are admitted by the connection limit before their handshakes,
+/// and at most [`TlsIncoming::MAX_HANDSHAKES`] handshakes run at once.
+pub(super) struct TlsIncoming<I: Accept> {
+    incoming: I,
+    acceptor: TlsAcceptor,
+    limit: Option<Arc<ConnectionLimit>>,
+    admission: Option<Admission>,
+    handshakes: FuturesUnordered<Timeout<tokio_rustls::Accept<Admitted<I::Conn>>>>,
+}
+
+impl<I: Accept> TlsIncoming<I> {
+    const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+    const MAX_HANDSHAKES: usize = 256;
+
+    pub(super) fn new(
+        incoming: I,
+        acceptor: TlsAcceptor,
+        limit: Option<Arc<ConnectionLimit>>,
+    ) -> Self {
+        Self {
+            incoming,
+            acceptor,
+            limit,
+            admission: None,
+            handshakes: FuturesUnordered::new(),
+        }
+    }
+}
+
+impl<I> Accept for TlsIncoming<I>
+where
+    I: Accept<Error = io::Error> + Unpin,
+    I::Conn: AsyncRead + AsyncWrite + Unpin,
+{
+    type Conn = TlsStream<Admitted<I::Conn>>;
+    type Error = io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let this = self.get_mut();
+
+        loop {
+            // handshake failures are the fault of clients, so they do not stop the server
+            while let Poll::Ready(Some(ret)) = this.handshakes.poll_next_unpin(cx) {
+                match ret {
+                    Ok(Ok(stream)) => return Poll::Ready(Some(Ok(stream))),
+                    Ok(Err(e)) => tracing::debug!("TLS handshake failed: {}", e),
+                    Err(_) => tracing::debug!("TLS handshake timed out"),
+                }
+            }
+
+            if this.handshakes.len() >= Self::MAX_HANDSHAKES {
+                return Poll::Pending;
+            }
+            if let Some(ref limit) = this.limit {
+                if this.admission.is_none() {
+                    this.admission = Some(futures::ready!(limit.poll_admit(cx)));
+                }
+            }
+
+            match Pin::new(&mut this.incoming).poll_accept(cx) {
+                Poll::Ready(Some(Ok(stream))) => {
+                    let stream = Admitted {
+                        stream,
+                        _admission: this.admission.take(),
+                    };
+                    let handshake = this.acceptor.accept(stream);
+                    let handshake = tokio::time::timeout(Self::HANDSHAKE_TIMEOUT, handshake);
+                    this.handshakes.push(handshake);
+                }
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) if this.handshakes.is_empty() => return Poll::Ready(None),
+                Poll::Ready(None) | Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+/// A connection holding its admission to the connection limit until closed
+pub(super) struct Admitted<C> {
+    stream: C,
+    _admission: Option<Admission>,
+}
+
+impl<C: AsyncRead + Unpin> AsyncRead for Admitted<C> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+    }
+}
+
+impl<C: AsyncWrite + Unpin> AsyncWrite for Admitted<C> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+impl<C: Connection> Connection for Admitted<C> {
+    fn info(&self) -> ConnectionInfo {
+        self.stream.info()
+    }
+}
+
+impl<C> Connection for TlsStream<C>
+where
+    C: Connection,
+{
+    fn info(&self) -> ConnectionInfo {
+        let (stream, session) = self.get_ref();
+        let tls = TlsInfo::new(
+            session.server_name().map(str::to_owned),
+            session.alpn_protocol().map(<[u8]>::to_vec),
+        );
+        stream.info().with_tls(tls)
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn tls_handshake() {
+    use crate::functional::handler;
+    use crate::server::ServerBuilder;
+
+    use futures::channel::oneshot;
+    use rustls::{ClientConfig, RootCertStore, ServerName};
+    use std::convert::TryFrom;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio_rustls::TlsConnector;
+
+    const ADDR: &str = "127.0.0.1:38743";
+
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+    let dir = std::env::temp_dir().join(format!("nuclear-tls-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+    fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+    fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+
+    async fn sni(req: Request) -> String {
+        let tls = req.connection_info().and_then(|c| c.tls());
+        let name = tls.and_then(|t| t.server_name()).unwrap_or("");
+        format!("{} {}", req.scheme(), name)
+    }
+
+    let mut builder = ServerBuilder::new();
+    builder.tls(TlsConfig::from_pem_files(&cert_path, &key_path).unwrap());
+    let (tx, rx) = oneshot::channel::<()>();
+    let server = builder.build(handler(sni).boxed());
+    let task = tokio::spawn(server.run_with_shutdown(ADDR, async {
+        let _ = rx.await;
+    }));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let mut roots = RootCertStore::empty();
+    roots
+        .add(&Certificate(cert.serialize_der().unwrap()))
+        .unwrap();
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let connector = TlsConnector::from(Arc::new(config));
+    let stream = TcpStream::connect(ADDR).await.unwrap();
+    let name = ServerName::try_from("localhost").unwrap();
+    let mut stream = connector.connect(name, stream).await.unwrap();
+
+    let req = b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+    stream.write_all(req).await.unwrap();
+    let mut res = String::new();
+    let _ = stream.read_to_string(&mut res).await;
+    assert!(res.ends_with("https localhost"), "{}", res);
+
+    tx.send(()).unwrap();
+    task.await.unwrap().unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn tls_reload() {
+    let dir = std::env::temp_dir().join(format!("nuclear-reload-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+    let write_cert = || {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+        fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+        load_certified_key(&cert_path, &key_path).unwrap().cert[0].clone()
+    };
+    let current = |config: &TlsConfig| config.resolver.key.read().unwrap().cert[0].clone();
+
+    let first = write_cert();
+    let mut config = TlsConfig::from_pem_files(&cert_path, &key_path).unwrap();
+    config.reload_interval(Duration::from_millis(50));
+    let _ = config.acceptor(Vec::new());
+    assert_eq!(current(&config), first);
+
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    let second = write_cert();
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(current(&config), second);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn tls_handshake_limit() {
+    use crate::functional::handler;
+    use crate::server::ServerBuilder;
+
+    use futures::channel::oneshot;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    const ADDR: &str = "127.0.0.1:38747";
+
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+    let dir = std::env::temp_dir().join(format!("nuclear-limit-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+    fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+    fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+
+    let mut builder = ServerBuilder::new();
+    builder
+        .tls(TlsConfig::from_pem_files(&cert_path, &key_path).unwrap())
+        .max_connections(1)
+        .connection_queue(0);
+    let (tx, rx) = oneshot::channel::<()>();
+    let server = builder.build(handler(|_: Request| async { "ok" }).boxed());
+    let task = tokio::spawn(server.run_with_shutdown(ADDR, async {
+        let _ = rx.await;
+    }));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // a connection that never completes its handshake still counts
+    let idle = TcpStream::connect(ADDR).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    // the second connection is left in the backlog, so its handshake gets no reply
+    let mut second = TcpStream::connect(ADDR).await.unwrap();
+    second.write_all(b"\x16\x03\x01\x00\x00").await.unwrap();
+    let mut buf = [0; 64];
+    let wait = Duration::from_millis(200);
+    assert!(tokio::time::timeout(wait, second.read(&mut buf))
+        .await
+        .is_err());
+
+    drop(idle);
+    assert!(
+        tokio::time::timeout(Duration::from_secs(2), second.read(&mut buf))
+            .await
+            .is_ok()
+    );
+
+    drop(second);
+    tx.send(()).unwrap();
+    task.await.unwrap().unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+}
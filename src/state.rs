@@ -1,52 +1,297 @@
 #![allow(unsafe_code)]
 
-use std::any::TypeId;
+use crate::handler::Handler;
+use crate::http::StatusCode;
+use crate::response::Response;
+
+use std::any::{type_name, Any, TypeId};
 use std::cell::Cell;
+use std::fmt;
 use std::ptr::NonNull;
 use std::sync::Arc;
 
 thread_local! {
-    static CURRENT_STATE: Cell<Option<ErasedStateRef>> = Cell::new(None)
+    static CURRENT_STATE: Cell<Option<NonNull<StateFrame>>> = const { Cell::new(None) }
 }
 
-#[derive(Clone, Copy)]
-struct ErasedStateRef {
+/// A node of the state stack, which lives on the call stack of [`enter`]
+struct StateFrame {
     ptr: NonNull<()>, // &Arc<S>
     id: TypeId,
+    clone: CloneFn,
+    prev: Option<NonNull<StateFrame>>,
 }
 
-pub fn enter<S, R>(state: &Arc<S>, f: impl FnOnce() -> R) -> R
+/// A type-erased `Arc<S>`
+type ErasedState = Box<dyn Any + Send + Sync>;
+
+type CloneFn = unsafe fn(NonNull<()>) -> ErasedState;
+
+unsafe fn clone_state<S>(ptr: NonNull<()>) -> ErasedState
 where
     S: Send + Sync + 'static,
 {
-    struct Guard<'a> {
-        cell: &'a Cell<Option<ErasedStateRef>>,
-        prev: Option<ErasedStateRef>,
-    }
+    Box::new(Arc::clone(ptr.cast::<Arc<S>>().as_ref()))
+}
 
-    impl Drop for Guard<'_> {
-        fn drop(&mut self) {
-            self.cell.set(self.prev)
-        }
+struct Guard<'a> {
+    cell: &'a Cell<Option<NonNull<StateFrame>>>,
+    prev: Option<NonNull<StateFrame>>,
+}
+
+impl Drop for Guard<'_> {
+    fn drop(&mut self) {
+        self.cell.set(self.prev)
     }
+}
 
+/// Runs `f` with `state` pushed onto the state stack of the current thread.
+///
+/// States entered by outer scopes remain injectable unless shadowed by the same type.
+pub fn enter<S, R>(state: &Arc<S>, f: impl FnOnce() -> R) -> R
+where
+    S: Send + Sync + 'static,
+{
     CURRENT_STATE.with(|cell| {
-        let prev = cell.replace(Some(ErasedStateRef {
+        let frame = StateFrame {
             ptr: NonNull::from(state).cast(),
             id: TypeId::of::<S>(),
-        }));
+            clone: clone_state::<S>,
+            prev: cell.get(),
+        };
+        let prev = cell.replace(Some(NonNull::from(&frame)));
         let _guard = Guard { cell, prev };
         f()
     })
 }
 
+/// The states of the current thread, which can be entered on another thread
+pub struct StateSnapshot {
+    // from the outermost to the innermost
+    states: Vec<(TypeId, ErasedState, CloneFn)>,
+}
+
+/// Captures the state stack of the current thread.
+pub fn snapshot() -> StateSnapshot {
+    CURRENT_STATE.with(|cell| {
+        let mut states = Vec::new();
+        let mut cur = cell.get();
+        while let Some(ptr) = cur {
+            // SAFETY: a frame is popped before its `enter` call returns
+            let frame = unsafe { ptr.as_ref() };
+            let state = unsafe { (frame.clone)(frame.ptr) };
+            states.push((frame.id, state, frame.clone));
+            cur = frame.prev;
+        }
+        states.reverse();
+        StateSnapshot { states }
+    })
+}
+
+impl StateSnapshot {
+    /// Runs `f` with the captured states pushed onto the state stack of the current thread.
+    pub fn enter<R>(&self, f: impl FnOnce() -> R) -> R {
+        CURRENT_STATE.with(|cell| {
+            let mut frames: Vec<StateFrame> = self
+                .states
+                .iter()
+                .map(|&(id, ref state, clone)| StateFrame {
+                    ptr: NonNull::from(&**state).cast(),
+                    id,
+                    clone,
+                    prev: None,
+                })
+                .collect();
+
+            let mut top = cell.get();
+            for frame in frames.iter_mut() {
+                frame.prev = top;
+                top = Some(NonNull::from(&*frame));
+            }
+
+            let prev = cell.replace(top);
+            let _guard = Guard { cell, prev };
+            f()
+        })
+    }
+}
+
+/// Searches the state stack from the innermost scope outwards.
 pub fn inject<S>() -> Option<Arc<S>>
 where
     S: Send + Sync + 'static,
 {
     CURRENT_STATE.with(|cell| {
-        cell.get()
-            .filter(|s| s.id == TypeId::of::<S>())
-            .map(|s| unsafe { Arc::clone(s.ptr.cast::<Arc<S>>().as_ref()) })
+        let mut cur = cell.get();
+        while let Some(ptr) = cur {
+            // SAFETY: a frame is popped before its `enter` call returns
+            let frame = unsafe { ptr.as_ref() };
+            if frame.id == TypeId::of::<S>() {
+                return Some(unsafe { Arc::clone(frame.ptr.cast::<Arc<S>>().as_ref()) });
+            }
+            cur = frame.prev;
+        }
+        None
     })
 }
+
+/// A state attached to request extensions by [`WithRequestState`](crate::handler::WithRequestState)
+pub(crate) struct RequestState<S>(pub(crate) Arc<S>);
+
+pub fn try_inject<S>() -> Result<Arc<S>, StateError>
+where
+    S: Send + Sync + 'static,
+{
+    inject::<S>().ok_or_else(StateError::new::<S>)
+}
+
+#[derive(Debug, thiserror::Error)]
+pub struct StateError {
+    state: &'static str,
+    target: Option<&'static str>,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "StateError: failed to inject state <{}>", self.state)?;
+        if let Some(target) = self.target {
+            write!(f, " for <{}>", target)?;
+        }
+        Ok(())
+    }
+}
+
+impl StateError {
+    pub(crate) fn new<S>() -> Self {
+        Self {
+            state: type_name::<S>(),
+            target: None,
+        }
+    }
+
+    pub(crate) fn with_target<T: ?Sized>(mut self) -> Self {
+        self.target = Some(type_name::<T>());
+        self
+    }
+
+    /// The type name of the missing state
+    pub fn state(&self) -> &'static str {
+        self.state
+    }
+
+    /// The type name of the handler or middleware requiring the state
+    pub fn target(&self) -> Option<&'static str> {
+        self.target
+    }
+}
+
+impl From<StateError> for Response {
+    fn from(e: StateError) -> Self {
+        tracing::error!("{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR.into()
+    }
+}
+
+/// The states provided by enclosing [`WithState`](crate::handler::WithState)s
+/// while checking a handler tree
+#[derive(Default)]
+pub struct StateScope {
+    provided: Vec<TypeId>,
+    missing: Option<StateError>,
+}
+
+impl StateScope {
+    /// Runs `f` in a scope where `S` is provided.
+    pub fn provide<S>(&mut self, f: impl FnOnce(&mut Self))
+    where
+        S: Send + Sync + 'static,
+    {
+        self.provided.push(TypeId::of::<S>());
+        f(self);
+        self.provided.pop();
+    }
+
+    pub(crate) fn provide_ids(&mut self, ids: &[TypeId], f: impl FnOnce(&mut Self)) {
+        let len = self.provided.len();
+        self.provided.extend_from_slice(ids);
+        f(self);
+        self.provided.truncate(len);
+    }
+
+    /// Records `S` as missing if no enclosing scope provides it.
+    pub fn require<S, T>(&mut self)
+    where
+        S: Send + Sync + 'static,
+        T: ?Sized,
+    {
+        if self.missing.is_some() {
+            return;
+        }
+        if !self.provided.contains(&TypeId::of::<S>()) {
+            self.missing = Some(StateError::new::<S>().with_target::<T>());
+        }
+    }
+}
+
+/// Checks that every state required in the handler tree is provided.
+///
+/// Custom handlers are only checked if they implement [`Handler::check_state`].
+pub fn verify(h: &dyn Handler) -> Result<(), StateError> {
+    verify_provided(h, &[])
+}
+
+/// Like [`verify`], with the states of `ids` provided from outside the handler tree.
+pub(crate) fn verify_provided(h: &dyn Handler, ids: &[TypeId]) -> Result<(), StateError> {
+    let mut scope = StateScope::default();
+    scope.provide_ids(ids, |scope| h.check_state(scope));
+    match scope.missing {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+#[test]
+fn verify_state() {
+    use crate::functional::{handler, ref_handler};
+    use crate::request::Request;
+
+    struct App;
+    struct Db;
+
+    async fn needs_app(_: &App, _: Request) {}
+    async fn needs_db(_: &Db, _: Request) {}
+    async fn no_state(_: Request) {}
+
+    let h = ref_handler(needs_app).with_state(Arc::new(App));
+    assert!(verify(&h).is_ok());
+
+    let h = handler(no_state);
+    assert!(verify(&h).is_ok());
+
+    let h = ref_handler(needs_db).with_state(Arc::new(App));
+    let err = verify(&h).unwrap_err();
+    assert_eq!(err.state(), type_name::<Db>());
+
+    let h = ref_handler(needs_app)
+        .with_state(Arc::new(Db))
+        .with_state(Arc::new(App));
+    assert!(verify(&h).is_ok());
+}
+
+#[test]
+fn nested_state() {
+    let outer = Arc::new(1_u32);
+    let inner = Arc::new("inner");
+    let shadow = Arc::new(2_u32);
+
+    enter(&outer, || {
+        enter(&inner, || {
+            assert_eq!(inject::<u32>().as_deref(), Some(&1));
+            assert_eq!(inject::<&str>().as_deref(), Some(&"inner"));
+            enter(&shadow, || assert_eq!(inject::<u32>().as_deref(), Some(&2)));
+            assert_eq!(inject::<u32>().as_deref(), Some(&1));
+        });
+        assert!(inject::<&str>().is_none());
+    });
+    assert!(inject::<u32>().is_none());
+}
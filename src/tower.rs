@@ -0,0 +1,296 @@
+//! Adapters between nuclear and [`tower`](::tower)
+
+use crate::internal_prelude::*;
+
+use std::fmt;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use ::tower::{BoxError, Layer, Service};
+use futures::channel::{mpsc, oneshot};
+use futures::future;
+use futures::stream::{FuturesUnordered, StreamExt};
+
+/// A [`Handler`] as a [`Service`]
+///
+/// The service is always ready. Errors of the handler are passed through.
+#[derive(Clone)]
+pub struct HandlerService {
+    h: Arc<dyn Handler>,
+}
+
+impl HandlerService {
+    pub fn new(h: Box<dyn Handler>) -> Self {
+        Self { h: Arc::from(h) }
+    }
+}
+
+impl From<Box<dyn Handler>> for HandlerService {
+    fn from(h: Box<dyn Handler>) -> Self {
+        Self::new(h)
+    }
+}
+
+impl fmt::Debug for HandlerService {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HandlerService").finish()
+    }
+}
+
+impl Service<HyperRequest> for HandlerService {
+    type Response = HyperResponse;
+    type Error = Error;
+    type Future = BoxFuture<'static, Result<Self::Response>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: HyperRequest) -> Self::Future {
+        let h = Arc::clone(&self.h);
+        Box::pin(async move {
+            let res = h.handle(Request::from_hyper(req)).await?;
+            Ok(res.into_hyper())
+        })
+    }
+}
+
+/// The inner [`Service`] of the layers of a [`LayerMiddleware`], which calls the next handler
+///
+/// The next handler travels in the request extensions,
+/// so a layer must pass the request it receives on to `Next`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Next {
+    _priv: (),
+}
+
+type NextCall = (Request, oneshot::Sender<Result<Response>>);
+
+/// Sends the requests reaching [`Next`] back to the middleware holding the next handler
+struct NextHandle(mpsc::UnboundedSender<NextCall>);
+
+/// An error of the next handler, recovered as is by [`LayerMiddleware`]
+#[derive(Debug, thiserror::Error)]
+#[error(transparent)]
+struct NextError(Error);
+
+fn into_error(e: impl Into<BoxError>) -> Error {
+    match e.into().downcast::<NextError>() {
+        Ok(e) => e.0,
+        Err(e) => Error::msg(e),
+    }
+}
+
+impl Service<HyperRequest> for Next {
+    type Response = HyperResponse;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, mut req: HyperRequest) -> Self::Future {
+        let handle = req.extensions_mut().remove::<NextHandle>();
+        Box::pin(async move {
+            let handle = match handle {
+                Some(h) => h,
+                None => return Err("the next handler is missing in the request".into()),
+            };
+            let (tx, rx) = oneshot::channel();
+            if handle
+                .0
+                .unbounded_send((Request::from_hyper(req), tx))
+                .is_err()
+            {
+                return Err("the middleware has been dropped".into());
+            }
+            match rx.await {
+                Ok(Ok(res)) => Ok(res.into_hyper()),
+                Ok(Err(e)) => Err(NextError(e).into()),
+                Err(_) => Err("the middleware has been dropped".into()),
+            }
+        })
+    }
+}
+
+/// A [`Layer`] as a [`Middleware`]
+///
+/// The layer is applied once, so stateful layers such as concurrency limits
+/// are shared by all requests. The layered service is cloned for each request,
+/// as tower services usually are.
+pub struct LayerMiddleware<S> {
+    svc: S,
+}
+
+impl<S> LayerMiddleware<S> {
+    pub fn new<L>(layer: L) -> Self
+    where
+        L: Layer<Next, Service = S>,
+    {
+        Self {
+            svc: layer.layer(Next::default()),
+        }
+    }
+}
+
+impl<S> Middleware for LayerMiddleware<S>
+where
+    S: Service<HyperRequest, Response = HyperResponse> + Clone + Send + Sync + 'static,
+    S::Error: Into<BoxError>,
+    S::Future: Send,
+{
+    fn handle<'t, 'n, 'a>(
+        &'t self,
+        mut req: Request,
+        next: &'n dyn Handler,
+    ) -> BoxFuture<'a, Result<Response>>
+    where
+        't: 'a,
+        'n: 'a,
+        Self: 'a,
+    {
+        let (tx, mut calls) = mpsc::unbounded::<NextCall>();
+        req.extensions_mut().insert(NextHandle(tx));
+
+        let mut svc = self.svc.clone();
+        let mut call = Box::pin(async move {
+            future::poll_fn(|cx| svc.poll_ready(cx))
+                .await
+                .map_err(into_error)?;
+            svc.call(req.into_hyper()).await.map_err(into_error)
+        });
+
+        // The calls of `Next` are served here, where the next handler is borrowed.
+        let mut running = FuturesUnordered::new();
+        Box::pin(async move {
+            let res = future::poll_fn(|cx| {
+                while let Poll::Ready(Some((req, tx))) = calls.poll_next_unpin(cx) {
+                    running.push(async move {
+                        let _ = tx.send(next.handle(req).await);
+                    });
+                }
+                while let Poll::Ready(Some(())) = running.poll_next_unpin(cx) {}
+                call.as_mut().poll(cx)
+            })
+            .await?;
+            Ok(Response::from_hyper(res))
+        })
+    }
+}
+
+#[test]
+fn tower_layer() {
+    use crate::functional::handler;
+    use crate::http::{HeaderValue, StatusCode};
+
+    use futures::executor::block_on;
+    use futures::future::{MapOk, TryFutureExt};
+
+    #[derive(Clone)]
+    struct Tag<S>(S);
+
+    struct TagLayer;
+
+    impl<S> Layer<S> for TagLayer {
+        type Service = Tag<S>;
+
+        fn layer(&self, inner: S) -> Self::Service {
+            Tag(inner)
+        }
+    }
+
+    impl<S> Service<HyperRequest> for Tag<S>
+    where
+        S: Service<HyperRequest, Response = HyperResponse>,
+    {
+        type Response = HyperResponse;
+        type Error = S::Error;
+        type Future = MapOk<S::Future, fn(HyperResponse) -> HyperResponse>;
+
+        fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            self.0.poll_ready(cx)
+        }
+
+        fn call(&mut self, req: HyperRequest) -> Self::Future {
+            self.0.call(req).map_ok(|mut res| {
+                let value = HeaderValue::from_static("tower");
+                res.headers_mut().insert("x-layer", value);
+                res
+            })
+        }
+    }
+
+    let h = handler(|_: Request| async { StatusCode::ACCEPTED });
+    let mut svc = HandlerService::new(h.wrap(LayerMiddleware::new(TagLayer)).boxed());
+
+    let res = block_on(svc.call(HyperRequest::default())).unwrap();
+    assert_eq!(res.status(), StatusCode::ACCEPTED);
+    assert_eq!(res.headers()["x-layer"], "tower");
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn tower_timeout() {
+    use crate::error::StatusError;
+    use crate::functional::handler;
+    use crate::http::{StatusCode, Uri};
+
+    use std::time::Duration;
+
+    use ::tower::timeout::TimeoutLayer;
+
+    async fn endpoint(uri: Uri) -> Result<Response> {
+        match uri.path() {
+            "/slow" => tokio::time::sleep(Duration::from_secs(10)).await,
+            "/error" => return Err(StatusError::new(StatusCode::CONFLICT).into()),
+            _ => {}
+        }
+        Ok(Response::text("done"))
+    }
+
+    let layer = TimeoutLayer::new(Duration::from_millis(50));
+    let h = handler(endpoint).wrap(LayerMiddleware::new(layer));
+    let req = |uri: &'static str| {
+        let req = crate::http::Request::builder().uri(uri).body(Body::empty());
+        Request::from_hyper(req.unwrap())
+    };
+
+    let res = h.handle(req("/")).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let err = h.handle(req("/slow")).await.unwrap_err();
+    assert_eq!(err.to_string(), "request timed out");
+
+    let err = h.handle(req("/error")).await.unwrap_err();
+    let err = err.downcast_ref::<StatusError>().unwrap();
+    assert_eq!(err.status, StatusCode::CONFLICT);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn tower_concurrency_limit() {
+    use crate::functional::handler;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use ::tower::limit::ConcurrencyLimitLayer;
+
+    static RUNNING: AtomicUsize = AtomicUsize::new(0);
+    static MAX_RUNNING: AtomicUsize = AtomicUsize::new(0);
+
+    async fn endpoint(_: Request) {
+        let running = RUNNING.fetch_add(1, Ordering::SeqCst) + 1;
+        MAX_RUNNING.fetch_max(running, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        RUNNING.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    let h = handler(endpoint).wrap(LayerMiddleware::new(ConcurrencyLimitLayer::new(1)));
+    let calls = (0..4).map(|_| h.handle(Request::from_hyper(HyperRequest::default())));
+    for ret in future::join_all(calls).await {
+        ret.unwrap();
+    }
+    assert_eq!(MAX_RUNNING.load(Ordering::SeqCst), 1);
+}
@@ -0,0 +1,167 @@
+use crate::http::StatusCode;
+use crate::response::Response;
+
+use std::borrow::Cow;
+use std::fmt;
+
+/// Checks a value after deserialization
+pub trait Validate {
+    /// Adds every violation found in `self` to `errors`.
+    fn validate(&self, errors: &mut ValidationErrors);
+}
+
+impl<T: Validate> Validate for Vec<T> {
+    fn validate(&self, errors: &mut ValidationErrors) {
+        for (i, item) in self.iter().enumerate() {
+            errors.nested(i.to_string(), item);
+        }
+    }
+}
+
+impl<T: Validate> Validate for Option<T> {
+    fn validate(&self, errors: &mut ValidationErrors) {
+        if let Some(value) = self {
+            value.validate(errors)
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Violation {
+    pub field: Cow<'static, str>,
+    pub message: Cow<'static, str>,
+}
+
+#[derive(Debug, Default, thiserror::Error)]
+pub struct ValidationErrors {
+    violations: Vec<Violation>,
+}
+
+impl fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ValidationErrors:")?;
+        for v in &self.violations {
+            write!(f, " {}: {};", v.field, v.message)?;
+        }
+        Ok(())
+    }
+}
+
+impl ValidationErrors {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(
+        &mut self,
+        field: impl Into<Cow<'static, str>>,
+        message: impl Into<Cow<'static, str>>,
+    ) {
+        self.violations.push(Violation {
+            field: field.into(),
+            message: message.into(),
+        })
+    }
+
+    /// Adds a violation when `cond` is false.
+    pub fn check(
+        &mut self,
+        cond: bool,
+        field: impl Into<Cow<'static, str>>,
+        message: impl Into<Cow<'static, str>>,
+    ) {
+        if !cond {
+            self.add(field, message)
+        }
+    }
+
+    /// Validates `value` and prefixes its violations with `field`.
+    pub fn nested<T: Validate + ?Sized>(&mut self, field: impl Into<Cow<'static, str>>, value: &T) {
+        let mut inner = ValidationErrors::new();
+        value.validate(&mut inner);
+        if inner.is_empty() {
+            return;
+        }
+        let field = field.into();
+        for v in inner.violations {
+            self.violations.push(Violation {
+                field: format!("{}.{}", field, v.field).into(),
+                message: v.message,
+            })
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.violations.is_empty()
+    }
+
+    pub fn violations(&self) -> &[Violation] {
+        &self.violations
+    }
+
+    pub fn into_result(self) -> Result<(), Self> {
+        if self.is_empty() {
+            Ok(())
+        } else {
+            Err(self)
+        }
+    }
+}
+
+pub fn validate<T: Validate + ?Sized>(value: &T) -> Result<(), ValidationErrors> {
+    let mut errors = ValidationErrors::new();
+    value.validate(&mut errors);
+    errors.into_result()
+}
+
+impl From<ValidationErrors> for Response {
+    fn from(e: ValidationErrors) -> Self {
+        let violations: Vec<_> = e
+            .violations
+            .iter()
+            .map(|v| serde_json::json!({ "field": v.field, "message": v.message }))
+            .collect();
+        let value = serde_json::json!({ "violations": violations });
+        match Response::json(value) {
+            Ok(res) => res.with_status(StatusCode::UNPROCESSABLE_ENTITY),
+            Err(_) => StatusCode::UNPROCESSABLE_ENTITY.into(),
+        }
+    }
+}
+
+#[test]
+fn nested_violations() {
+    struct Item {
+        qty: u32,
+    }
+
+    impl Validate for Item {
+        fn validate(&self, errors: &mut ValidationErrors) {
+            errors.check(self.qty > 0, "qty", "must be positive");
+        }
+    }
+
+    struct Order {
+        name: String,
+        items: Vec<Item>,
+    }
+
+    impl Validate for Order {
+        fn validate(&self, errors: &mut ValidationErrors) {
+            errors.check(!self.name.is_empty(), "name", "must not be empty");
+            errors.nested("items", &self.items);
+        }
+    }
+
+    let order = Order {
+        name: String::new(),
+        items: vec![Item { qty: 1 }, Item { qty: 0 }],
+    };
+
+    let errors = validate(&order).unwrap_err();
+    let fields: Vec<_> = errors.violations().iter().map(|v| &*v.field).collect();
+    assert_eq!(fields, ["name", "items.1.qty"]);
+
+    let res = Response::from(errors);
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}